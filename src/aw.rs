@@ -1,8 +1,19 @@
 extern crate byteorder;
 
-use std::io::{self, Write};
-use byteorder::{ByteOrder, LE, WriteBytesExt};
-use encoding::{all::WINDOWS_1252, Encoding, EncoderTrap};
+use failure;
+
+use std::borrow::Cow;
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+
+use codepage::CodePage;
+
+/// The most bytes the name, description and action of an object can have in the cache
+pub const MAX_TEXT_LEN: usize = u8::MAX as usize;
+/// The most bytes of object data the cache can hold for an object
+pub const MAX_DATA_LEN: usize = u16::MAX as usize;
 
 #[derive(Debug, Clone, Default)]
 pub struct Object {
@@ -34,17 +45,49 @@ pub struct Location {
 
 
 
+/// Width of a cell in coordinate units
+const CELL_SIZE: i32 = 1000;
+/// Lowest x or z whose cell fits in an i16
+pub const MIN_COORD: i32 = i16::MIN as i32 * CELL_SIZE;
+/// Highest x or z whose cell fits in an i16
+pub const MAX_COORD: i32 = i16::MAX as i32 * CELL_SIZE + CELL_SIZE - 1;
+
+/// The cell a position is in. Cells start at multiples of 1000 in every quadrant, as in the AW browser,
+/// so x = -1 is in cell -1 rather than cell 0. The position must be within `MIN_COORD` and `MAX_COORD`.
+pub fn cell(x: i32, z: i32) -> (i16, i16) {
+    (x.div_euclid(CELL_SIZE) as i16, z.div_euclid(CELL_SIZE) as i16)
+}
+
 impl Object {
+    /// The cell the object is in, and its position within the cell. The offsets are never negative.
     pub fn location(&self) -> Location {
+        let (cell_x, cell_z) = cell(self.x, self.z);
         Location {
-            cell_x: (self.x/1000) as i16,
-            cell_z: (self.z/1000) as i16,
-            obj_x: (self.x%1000) as i16,
-            obj_z: (self.z%1000) as i16,
+            cell_x,
+            cell_z,
+            obj_x: self.x.rem_euclid(CELL_SIZE) as i16,
+            obj_z: self.z.rem_euclid(CELL_SIZE) as i16,
             obj_y: self.y
         }
     }
-    pub fn write<W: Write>(&self, mut w: W) -> io::Result<()> {
+    /// The name, description and action as stored in the cache
+    pub fn encode_text(&self, code_page: &CodePage) -> EncodedText {
+        let (name, lost_name) = code_page.encode(&self.name);
+        let (desc, lost_desc) = code_page.encode(&self.desc);
+        let (action, lost_action) = code_page.encode(&self.action);
+        EncodedText {
+            fields: [name, desc, action],
+            lost: [lost_name, lost_desc, lost_action]
+        }
+    }
+
+    /// Writes the object as stored in the cache, with text from `encode_text`. Fails without writing anything if a
+    /// field is too long.
+    pub fn write_encoded<W: Write>(&self, mut w: W, text: &EncodedText) -> io::Result<()> {
+        if let Some(too_long) = text.too_long(self.data.len()).into_iter().next() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, too_long));
+        }
+        let [ref name, ref desc, ref action] = text.fields;
         let loc = self.location();
         w.write_i32::<LE>(self.type_)?;
         w.write_i32::<LE>(self.id)?;
@@ -57,14 +100,300 @@ impl Object {
         w.write_i16::<LE>(self.yaw)?;
         w.write_i16::<LE>(self.tilt)?;
         w.write_i16::<LE>(self.roll)?;
-        w.write_u8(self.name.len() as u8)?;
-        w.write_u8(self.desc.len() as u8)?;
-        w.write_u8(self.action.len() as u8)?;
+        w.write_u8(name.len() as u8)?;
+        w.write_u8(desc.len() as u8)?;
+        w.write_u8(action.len() as u8)?;
         w.write_u16::<LE>(self.data.len() as u16)?;
-        w.write_all(&WINDOWS_1252.encode(&self.name, EncoderTrap::Replace).unwrap())?;
-        w.write_all(&WINDOWS_1252.encode(&self.desc, EncoderTrap::Replace).unwrap())?;
-        w.write_all(&WINDOWS_1252.encode(&self.action, EncoderTrap::Replace).unwrap())?;
+        w.write_all(name)?;
+        w.write_all(desc)?;
+        w.write_all(action)?;
         w.write_all(&self.data)?;
         Ok(())
-    } 
-}
\ No newline at end of file
+    }
+
+    /// Cuts the name, description and action down to what the cache can hold, between characters.
+    /// Object data is left alone, as cutting it short would leave it meaningless.
+    pub fn truncate_text(&mut self, code_page: &CodePage) {
+        truncate_encoded(&mut self.name, MAX_TEXT_LEN, code_page);
+        truncate_encoded(&mut self.desc, MAX_TEXT_LEN, code_page);
+        truncate_encoded(&mut self.action, MAX_TEXT_LEN, code_page);
+    }
+
+    /// Enough to tell the object apart in messages
+    fn describe(&self) -> String {
+        format!("object of citizen {} at {} {} {} ({:?})", self.citnum, self.x, self.y, self.z, self.name)
+    }
+
+    /// Reads an object written by `write`. The cell is needed to restore the absolute position.
+    pub fn read<R: Read>(mut r: R, cell_x: i16, cell_z: i16, code_page: &CodePage) -> io::Result<Self> {
+        let type_ = r.read_i32::<LE>()?;
+        let id = r.read_i32::<LE>()?;
+        let number = r.read_i32::<LE>()?;
+        let citnum = r.read_i32::<LE>()?;
+        let time = r.read_i32::<LE>()?;
+        let obj_x = r.read_i16::<LE>()?;
+        let y = r.read_i32::<LE>()?;
+        let obj_z = r.read_i16::<LE>()?;
+        let yaw = r.read_i16::<LE>()?;
+        let tilt = r.read_i16::<LE>()?;
+        let roll = r.read_i16::<LE>()?;
+        let mut name = vec![0; r.read_u8()? as usize];
+        let mut desc = vec![0; r.read_u8()? as usize];
+        let mut action = vec![0; r.read_u8()? as usize];
+        let mut data = vec![0; r.read_u16::<LE>()? as usize];
+        r.read_exact(&mut name)?;
+        r.read_exact(&mut desc)?;
+        r.read_exact(&mut action)?;
+        r.read_exact(&mut data)?;
+        Ok(Object {
+            type_,
+            id,
+            number,
+            citnum,
+            time,
+            x: i32::from(cell_x) * CELL_SIZE + i32::from(obj_x),
+            y,
+            z: i32::from(cell_z) * CELL_SIZE + i32::from(obj_z),
+            yaw,
+            tilt,
+            roll,
+            name: code_page.decode(&name),
+            desc: code_page.decode(&desc),
+            action: code_page.decode(&action),
+            data
+        })
+    }
+}
+
+const TEXT_FIELDS: [&str; 3] = ["name", "description", "action"];
+
+/// The name, description and action of an object, encoded for the cache
+#[derive(Debug, Clone)]
+pub struct EncodedText {
+    fields: [Vec<u8>; 3],
+    /// Characters the code page lacks in each field, written as '?'
+    lost: [usize; 3]
+}
+
+impl EncodedText {
+    /// The fields too long to be stored in the cache, along with the data if `data_len` is
+    pub fn too_long(&self, data_len: usize) -> Vec<TooLong> {
+        let text = TEXT_FIELDS.iter().zip(&self.fields).map(|(&field, bytes)| (field, bytes.len(), MAX_TEXT_LEN));
+        text.chain(Some(("data", data_len, MAX_DATA_LEN)))
+            .filter(|&(_, len, max)| len > max)
+            .map(|(field, len, max)| TooLong { field, len, max })
+            .collect()
+    }
+
+    /// The fields with characters the code page lacks, and how many of them
+    pub fn lost_characters(&self) -> Vec<(&'static str, usize)> {
+        TEXT_FIELDS.iter().cloned().zip(self.lost.iter().cloned()).filter(|&(_, lost)| lost > 0).collect()
+    }
+}
+
+/// Shortens text, between characters, until it encodes to at most `max` bytes
+fn truncate_encoded(text: &mut String, max: usize, code_page: &CodePage) {
+    let mut len = 0;
+    let mut buf = [0u8; 4];
+    for (index, c) in text.char_indices() {
+        len += code_page.encode(c.encode_utf8(&mut buf)).0.len();
+        if len > max {
+            text.truncate(index);
+            return;
+        }
+    }
+}
+
+/// A field of an object that is too long to be stored in the cache
+#[derive(Debug)]
+pub struct TooLong {
+    pub field: &'static str,
+    pub len: usize,
+    pub max: usize
+}
+
+impl fmt::Display for TooLong {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is {} bytes, the cache allows {}", self.field, self.len, self.max)
+    }
+}
+
+impl error::Error for TooLong {
+    fn description(&self) -> &str {
+        "Object field too long"
+    }
+}
+
+/// What to do with objects that have fields too long for the cache
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LongFields {
+    /// Shorten the text. Objects with too much data are dropped, as data cannot be shortened.
+    Truncate,
+    Drop,
+    /// Stop with an error
+    Fail
+}
+
+/// Fits objects on their way into the cache: applies a `LongFields` policy, and reports every object it changes
+/// along with every object that has characters the cache's code page lacks
+pub struct FieldLimits {
+    policy: LongFields,
+    code_page: CodePage,
+    quiet: bool,
+    pub truncated: u64,
+    pub dropped: u64,
+    /// Objects with characters written as '?'
+    pub lossy: u64
+}
+
+impl FieldLimits {
+    pub fn new(policy: LongFields, code_page: CodePage) -> Self {
+        FieldLimits {
+            policy,
+            code_page,
+            quiet: false,
+            truncated: 0,
+            dropped: 0,
+            lossy: 0
+        }
+    }
+
+    /// Applies the policy without reporting anything, for measuring cells against a budget before the
+    /// conversion that reports. Objects that would stop the program are dropped instead.
+    pub fn quiet(policy: LongFields, code_page: CodePage) -> Self {
+        FieldLimits {
+            quiet: true,
+            ..FieldLimits::new(policy, code_page)
+        }
+    }
+
+    /// The object as it can be stored along with its encoded text, or `None` if it is to be left out
+    pub fn apply<'a>(&mut self, object: &'a Object) -> Result<Option<(Cow<'a, Object>, EncodedText)>, failure::Error> {
+        let text = object.encode_text(&self.code_page);
+        let too_long = text.too_long(object.data.len());
+        let (object, text) = if too_long.is_empty() {
+            (Cow::Borrowed(object), text)
+        } else {
+            let problems = too_long.iter().map(TooLong::to_string).collect::<Vec<_>>().join(", ");
+            let text_only = too_long.iter().all(|field| field.field != "data");
+            match self.policy {
+                LongFields::Fail if !self.quiet => bail!("The {} is too long: {}", object.describe(), problems),
+                LongFields::Truncate if text_only => {
+                    if !self.quiet {
+                        eprintln!("Truncated {}: {}", object.describe(), problems);
+                    }
+                    self.truncated += 1;
+                    let mut object = object.clone();
+                    object.truncate_text(&self.code_page);
+                    let text = object.encode_text(&self.code_page);
+                    (Cow::Owned(object), text)
+                },
+                _ => {
+                    if !self.quiet {
+                        eprintln!("Dropped {}: {}", object.describe(), problems);
+                    }
+                    self.dropped += 1;
+                    return Ok(None);
+                }
+            }
+        };
+        let lost = text.lost_characters();
+        if !lost.is_empty() {
+            self.lossy += 1;
+            if !self.quiet {
+                let fields = lost.iter().map(|&(field, count)| format!("{} in the {}", count, field)).collect::<Vec<_>>().join(", ");
+                eprintln!("Characters {} lacks were written as '?' in {}: {}", self.code_page.name(), object.describe(), fields);
+            }
+        }
+        Ok(Some((object, text)))
+    }
+
+    /// Reports how many objects were changed, if any
+    pub fn report(&self) {
+        if self.quiet {
+            return;
+        }
+        if self.truncated > 0 || self.dropped > 0 {
+            eprintln!("{} objects truncated and {} dropped for having fields too long for the cache", self.truncated, self.dropped);
+        }
+        if self.lossy > 0 {
+            eprintln!("{} objects had characters {} lacks written as '?'", self.lossy, self.code_page.name());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Coordinates in thousandths of a cell, the cell they are in and the offset within it
+    const CASES: [(i32, i16, i16); 16] = [
+        (0, 0, 0),
+        (999, 0, 999),
+        (1000, 1, 0),
+        (1500, 1, 500),
+        (-1, -1, 999),
+        (-999, -1, 1),
+        (-1000, -1, 0),
+        (-1001, -2, 999),
+        (-1500, -2, 500),
+        (32_767_000, 32767, 0),
+        (MAX_COORD, 32767, 999),
+        (32_766_999, 32766, 999),
+        (-32_767_000, -32767, 0),
+        (-32_767_001, -32768, 999),
+        (MIN_COORD, -32768, 0),
+        (-32_766_999, -32767, 1)
+    ];
+
+    #[test]
+    fn cells_along_each_axis() {
+        for &(coord, cell_number, _) in CASES.iter() {
+            assert_eq!(cell(coord, 0), (cell_number, 0), "x = {}", coord);
+            assert_eq!(cell(0, coord), (0, cell_number), "z = {}", coord);
+        }
+    }
+
+    #[test]
+    fn locations_in_every_quadrant() {
+        for &(x, cell_x, obj_x) in CASES.iter() {
+            for &(z, cell_z, obj_z) in CASES.iter() {
+                let object = Object { x, y: -5, z, ..Object::default() };
+                let loc = object.location();
+                assert_eq!((loc.cell_x, loc.cell_z, loc.obj_x, loc.obj_y, loc.obj_z), (cell_x, cell_z, obj_x, -5, obj_z),
+                    "x = {}, z = {}", x, z);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_text_is_encoded_once_and_fits() {
+        let code_page = CodePage::from_label("windows-1251", false).unwrap();
+        let mut limits = FieldLimits::quiet(LongFields::Truncate, code_page);
+        let object = Object { name: "\u{e9}".repeat(300), desc: "\u{416}".repeat(10), ..Object::default() };
+        let (fitted, text) = limits.apply(&object).unwrap().unwrap();
+        assert_eq!(fitted.name.chars().count(), MAX_TEXT_LEN);
+        assert!(text.too_long(fitted.data.len()).is_empty());
+        assert_eq!(text.lost_characters(), vec![("name", MAX_TEXT_LEN)]);
+        let mut encoded = Vec::new();
+        fitted.write_encoded(&mut encoded, &text).unwrap();
+        let mut written = Vec::new();
+        fitted.write_encoded(&mut written, &fitted.encode_text(&code_page)).unwrap();
+        assert_eq!(encoded, written);
+        assert_eq!((limits.truncated, limits.lossy), (1, 1));
+    }
+
+    #[test]
+    fn positions_survive_the_cache() {
+        let code_page = CodePage::default();
+        for &(x, cell_x, _) in CASES.iter() {
+            for &(z, cell_z, _) in CASES.iter() {
+                let mut bytes = Vec::new();
+                let object = Object { x, z, ..Object::default() };
+                object.write_encoded(&mut bytes, &object.encode_text(&code_page)).unwrap();
+                let object = Object::read(&bytes[..], cell_x, cell_z, &code_page).unwrap();
+                assert_eq!((object.x, object.z), (x, z));
+            }
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Picks a radius for every teleport so that the areas around them fit in a cell.dat size budget.
+//!
+//! Each area starts as the teleport's own cell and grows one ring of cells at a time, for as long as the cells a
+//! ring adds to the selection still fit. Cells shared by several areas only count once.
+
+use std::collections::{BTreeMap, HashSet};
+
+use ctree;
+use teleports::Teleport;
+
+/// The order teleports get to grow their areas in
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Growth {
+    /// Each teleport grows as far as it can before the next one in the file starts
+    Priority,
+    /// Every teleport grows by one ring in turn
+    Even
+}
+
+/// How many bytes each cell would take up in cell.dat, record header included
+#[derive(Debug, Default)]
+pub struct CellSizes {
+    /// By cell x then cell z
+    by_x: BTreeMap<(i16, i16), u64>,
+    /// By cell z then cell x
+    by_z: BTreeMap<(i16, i16), u64>
+}
+
+impl CellSizes {
+    /// Counts `bytes` more object data in a cell
+    pub fn add(&mut self, cell_x: i16, cell_z: i16, bytes: u64) {
+        let size = self.by_x.entry((cell_x, cell_z)).or_insert(ctree::RECORD_OVERHEAD);
+        *size += bytes;
+        self.by_z.insert((cell_z, cell_x), *size);
+    }
+
+    /// Lowest and highest cell x and z holding anything
+    fn extent(&self) -> Option<((i16, i16), (i16, i16))> {
+        let (min_x, max_x) = (self.by_x.keys().next()?.0, self.by_x.keys().next_back()?.0);
+        let (min_z, max_z) = (self.by_z.keys().next()?.0, self.by_z.keys().next_back()?.0);
+        Some(((min_x, max_x), (min_z, max_z)))
+    }
+
+    /// The cells holding anything on the square ring `radius` cells out from a cell
+    fn ring(&self, cell_x: i16, cell_z: i16, radius: i16) -> Vec<((i16, i16), u64)> {
+        let clamp = |coord: i32| coord.max(i32::from(i16::MIN)).min(i32::from(i16::MAX)) as i16;
+        let (x, z, r) = (i32::from(cell_x), i32::from(cell_z), i32::from(radius));
+        let mut cells = Vec::new();
+        // Sides running along z, corners included
+        for side_x in if r == 0 { vec![x] } else { vec![x - r, x + r] } {
+            if side_x < i32::from(i16::MIN) || side_x > i32::from(i16::MAX) {
+                continue;
+            }
+            let side_x = side_x as i16;
+            cells.extend(self.by_x.range((side_x, clamp(z - r))..=(side_x, clamp(z + r))).map(|(&cell, &size)| (cell, size)));
+        }
+        if r == 0 {
+            return cells;
+        }
+        // Sides running along x, corners left out
+        for side_z in [z - r, z + r] {
+            if side_z < i32::from(i16::MIN) || side_z > i32::from(i16::MAX) || x - r + 1 > x + r - 1 {
+                continue;
+            }
+            let side_z = side_z as i16;
+            let range = (side_z, clamp(x - r + 1))..=(side_z, clamp(x + r - 1));
+            cells.extend(self.by_z.range(range).map(|(&(cell_z, cell_x), &size)| ((cell_x, cell_z), size)));
+        }
+        cells
+    }
+}
+
+struct Area {
+    cell_x: i16,
+    cell_z: i16,
+    radius: Option<i16>,
+    done: bool
+}
+
+/// Works out the radius of every teleport, in file order. A teleport whose own cell does not fit gets `None`.
+/// `budget` counts the bytes the cells take up, not the blank cache they go into, and `max_radius` stops any
+/// area growing further.
+pub fn grow(teleports: &[Teleport], sizes: &CellSizes, budget: u64, max_radius: Option<i16>, growth: Growth) -> Vec<Option<i16>> {
+    let extent = sizes.extent();
+    let mut areas: Vec<Area> = teleports.iter().map(|teleport| Area {
+        cell_x: teleport.x,
+        cell_z: teleport.z,
+        radius: None,
+        done: false
+    }).collect();
+    let mut covered = HashSet::new();
+    let mut used = 0;
+    let mut grow_area = |area: &mut Area| {
+        let radius = match area.radius {
+            None => 0,
+            Some(radius) => {
+                // Once an area covers every cell with anything in it, growing adds nothing
+                let covers_all = extent.is_none_or(|((min_x, max_x), (min_z, max_z))| {
+                    let (x, z, r) = (i32::from(area.cell_x), i32::from(area.cell_z), i32::from(radius));
+                    x - r <= i32::from(min_x) && i32::from(max_x) <= x + r && z - r <= i32::from(min_z) && i32::from(max_z) <= z + r
+                });
+                if covers_all || radius == i16::MAX {
+                    area.done = true;
+                    return false;
+                }
+                radius + 1
+            }
+        };
+        if max_radius.is_some_and(|max_radius| radius > max_radius) {
+            area.done = true;
+            return false;
+        }
+        let added: Vec<_> = sizes.ring(area.cell_x, area.cell_z, radius).into_iter().filter(|(cell, _)| !covered.contains(cell)).collect();
+        let size: u64 = added.iter().map(|&(_, size)| size).sum();
+        if used + size > budget {
+            area.done = true;
+            return false;
+        }
+        used += size;
+        covered.extend(added.into_iter().map(|(cell, _)| cell));
+        area.radius = Some(radius);
+        true
+    };
+    match growth {
+        Growth::Priority => for area in &mut areas {
+            while !area.done {
+                grow_area(area);
+            }
+        },
+        Growth::Even => {
+            let mut grew = true;
+            while grew {
+                grew = false;
+                for area in areas.iter_mut().filter(|area| !area.done) {
+                    grew |= grow_area(area);
+                }
+            }
+        }
+    }
+    areas.into_iter().map(|area| area.radius).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn teleport(x: i16, z: i16) -> Teleport {
+        Teleport {
+            coords: format!("{} {}", x, z),
+            x,
+            z
+        }
+    }
+
+    /// Two 7 by 7 squares of cells taking up 100 bytes each, around 0 0 and 10 0
+    fn squares() -> CellSizes {
+        let mut sizes = CellSizes::default();
+        for &center in &[0, 10] {
+            for x in center - 3..=center + 3 {
+                for z in -3..=3 {
+                    sizes.add(x, z, 100 - ctree::RECORD_OVERHEAD);
+                }
+            }
+        }
+        sizes
+    }
+
+    #[test]
+    fn areas_grow_until_the_next_ring_goes_over_the_budget() {
+        let sizes = squares();
+        // A ring r out takes up 8r cells
+        for &(budget, radius) in &[(99, None), (100, Some(0)), (899, Some(0)), (900, Some(1)), (2499, Some(1)), (2500, Some(2))] {
+            assert_eq!(grow(&[teleport(0, 0)], &sizes, budget, None, Growth::Priority), vec![radius], "Budget {}", budget);
+        }
+    }
+
+    #[test]
+    fn priority_grows_each_teleport_in_turn_and_even_all_at_once() {
+        let sizes = squares();
+        let teleports = [teleport(0, 0), teleport(10, 0)];
+        assert_eq!(grow(&teleports, &sizes, 2500, None, Growth::Priority), vec![Some(2), None]);
+        assert_eq!(grow(&teleports, &sizes, 2500, None, Growth::Even), vec![Some(1), Some(1)]);
+    }
+
+    #[test]
+    fn shared_cells_count_once() {
+        let sizes = squares();
+        // The second teleport's own cell is already in the first one's area
+        let teleports = [teleport(0, 0), teleport(1, 0)];
+        assert_eq!(grow(&teleports, &sizes, 900, None, Growth::Priority), vec![Some(1), Some(0)]);
+    }
+
+    #[test]
+    fn areas_stop_at_the_maximum_radius_or_once_they_cover_everything() {
+        let sizes = squares();
+        assert_eq!(grow(&[teleport(0, 0)], &sizes, u64::MAX, Some(1), Growth::Even), vec![Some(1)]);
+        assert_eq!(grow(&[teleport(0, 0)], &sizes, u64::MAX, None, Growth::Even), vec![Some(13)]);
+    }
+}
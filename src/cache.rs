@@ -0,0 +1,469 @@
+use failure;
+use byteorder::{ByteOrder, LE};
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Cursor, Write};
+use std::ops::Range;
+use std::sync::atomic::Ordering;
+
+use aw::Object;
+use codepage::CodePage;
+use ctree::{self, DatFile, IdxFile};
+use propdump::PropdumpWriter;
+use RUNNING;
+
+/// Key kind of a cell's object data
+pub const CELL_DATA: u16 = 1;
+
+/// A key in cell.idx, stored as three little endian 16-bit values.
+/// Kind 0 holds a cell's sequence number. Keys that are not about a cell, such as the version key,
+/// decode to a meaningless `kind`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CellKey {
+    pub kind: u16,
+    pub cell_x: i16,
+    pub cell_z: i16
+}
+
+impl CellKey {
+    pub fn new(kind: u16, cell_x: i16, cell_z: i16) -> Self {
+        CellKey {
+            kind,
+            cell_x,
+            cell_z
+        }
+    }
+
+    pub fn from_bytes(key: &[u8]) -> Option<Self> {
+        if key.len() != 6 {
+            return None;
+        }
+        Some(CellKey {
+            kind: LE::read_u16(&key[0..2]),
+            cell_x: LE::read_i16(&key[2..4]),
+            cell_z: LE::read_i16(&key[4..6])
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; 6] {
+        let mut key = [0u8; 6];
+        LE::write_u16(&mut key[0..2], self.kind);
+        LE::write_i16(&mut key[2..4], self.cell_x);
+        LE::write_i16(&mut key[4..6], self.cell_z);
+        key
+    }
+}
+
+/// Somewhere the object data of whole cells can be written
+pub trait CellSink {
+    /// Stores `data` under the cell, appending if the cell already has data
+    fn write_cell(&mut self, cell_x: i16, cell_z: i16, data: &[u8]) -> Result<(), failure::Error>;
+
+    /// Called once every cell has been written
+    fn finish(&mut self) -> Result<(), failure::Error> {
+        Ok(())
+    }
+}
+
+impl<S: CellSink + ?Sized> CellSink for Box<S> {
+    fn write_cell(&mut self, cell_x: i16, cell_z: i16, data: &[u8]) -> Result<(), failure::Error> {
+        (**self).write_cell(cell_x, cell_z, data)
+    }
+
+    fn finish(&mut self) -> Result<(), failure::Error> {
+        (**self).finish()
+    }
+}
+
+/// An index and data file pair, such as cell.idx and cell.dat
+#[derive(Debug)]
+pub struct Cache {
+    pub idx: IdxFile,
+    pub dat: DatFile
+}
+
+/// Size of the blank42.dat that comes with AW 4.2
+const BLANK_DAT_SIZE: u64 = 16384;
+
+/// Roughly how big a cache made from blank42.dat gets holding `bytes` of object data in `cells` cells.
+/// Without a blank42.dat in the current directory, the one that comes with AW 4.2 is assumed.
+pub fn estimate_dat_size(cells: u64, bytes: u64) -> u64 {
+    let template = fs::metadata("blank42.dat").map(|metadata| metadata.len()).unwrap_or(BLANK_DAT_SIZE);
+    template + bytes + cells * ctree::RECORD_OVERHEAD
+}
+
+impl Cache {
+    /// Opens `name`.idx and `name`.dat
+    pub fn open(name: &str) -> Result<Self, failure::Error> {
+        let dat = DatFile::open(format!("{}.dat", name))?;
+        let idx = IdxFile::open(format!("{}.idx", name))?;
+        Ok(Cache {
+            idx,
+            dat
+        })
+    }
+
+    /// Replaces `name`.idx and `name`.dat with copies of the blank42 templates and opens them
+    pub fn create(name: &str) -> Result<Self, failure::Error> {
+        fs::copy("blank42.dat", format!("{}.dat", name))?;
+        fs::copy("blank42.idx", format!("{}.idx", name))?;
+        Cache::open(name)
+    }
+
+    pub fn cells<'a>(&'a self) -> CellReader<'a, 'a> {
+        CellReader::new(&self.idx, &self.dat)
+    }
+
+    /// Writes every object in the cache to `out`, cell by cell, until done or Ctrl-C is pressed
+    pub fn write_propdump<W: Write>(&self, out: &mut PropdumpWriter<W>, code_page: CodePage) -> Result<(), failure::Error> {
+        for object in self.cells().objects(code_page) {
+            if !RUNNING.load(Ordering::SeqCst) {
+                eprintln!("Quitting due to Ctrl-C");
+                break;
+            }
+            out.write(&object?)?;
+        }
+        Ok(())
+    }
+}
+
+impl CellSink for Cache {
+    fn write_cell(&mut self, cell_x: i16, cell_z: i16, data: &[u8]) -> Result<(), failure::Error> {
+        let key = CellKey::new(CELL_DATA, cell_x, cell_z).to_bytes();
+        ctree::insert_or_append(&self.idx, &self.dat, &key, data)?;
+        Ok(())
+    }
+}
+
+/// Counts what would be written to a cache instead of writing it, without gathering the objects
+#[derive(Debug, Default)]
+pub struct DryRun {
+    /// Cells that would be written
+    cells: HashSet<(i16, i16)>,
+    /// Bytes of object data
+    bytes: u64
+}
+
+impl DryRun {
+    /// Counts an object of `len` bytes in a cell
+    pub fn add(&mut self, cell_x: i16, cell_z: i16, len: usize) {
+        self.cells.insert((cell_x, cell_z));
+        self.bytes += len as u64;
+    }
+
+    pub fn cells(&self) -> u64 {
+        self.cells.len() as u64
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+/// What to do with cells that are already in the cache being updated
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UpdateMode {
+    /// Add the new objects to the cell
+    Append,
+    /// Throw away what the cell had before this run
+    Replace,
+    /// Add the new objects that the cell does not have yet
+    Dedupe
+}
+
+/// Writes into an existing cache instead of a blank one
+#[derive(Debug)]
+pub struct UpdatedCache {
+    cache: Cache,
+    mode: UpdateMode,
+    code_page: CodePage,
+    /// Cells written during this run
+    written: HashSet<(i16, i16)>
+}
+
+/// The fields that identify an object when deduplicating
+fn identity(object: &Object) -> (i32, i32, i32, i32, i32, String) {
+    (object.citnum, object.time, object.x, object.y, object.z, object.name.clone())
+}
+
+/// Parses the objects of a cell's data, along with where in it each one is
+fn parse_objects(data: &[u8], cell_x: i16, cell_z: i16, code_page: &CodePage) -> Result<Vec<(Object, Range<usize>)>, failure::Error> {
+    let mut cursor = Cursor::new(data);
+    let mut objects = Vec::new();
+    while (cursor.position() as usize) < data.len() {
+        let start = cursor.position() as usize;
+        let object = Object::read(&mut cursor, cell_x, cell_z, code_page)?;
+        objects.push((object, start..cursor.position() as usize));
+    }
+    Ok(objects)
+}
+
+impl UpdatedCache {
+    pub fn new(cache: Cache, mode: UpdateMode, code_page: CodePage) -> Self {
+        UpdatedCache {
+            cache,
+            mode,
+            code_page,
+            written: HashSet::new()
+        }
+    }
+}
+
+impl CellSink for UpdatedCache {
+    fn write_cell(&mut self, cell_x: i16, cell_z: i16, data: &[u8]) -> Result<(), failure::Error> {
+        let key = CellKey::new(CELL_DATA, cell_x, cell_z).to_bytes();
+        let first_write = self.written.insert((cell_x, cell_z));
+        match self.mode {
+            UpdateMode::Append => self.cache.write_cell(cell_x, cell_z, data),
+            UpdateMode::Replace => {
+                if first_write {
+                    ctree::take(&self.cache.idx, &self.cache.dat, &key)?;
+                }
+                self.cache.write_cell(cell_x, cell_z, data)
+            },
+            UpdateMode::Dedupe => {
+                let existing = match ctree::get(&self.cache.idx, &self.cache.dat, &key)? {
+                    Some(existing) => parse_objects(&existing, cell_x, cell_z, &self.code_page)?,
+                    None => return self.cache.write_cell(cell_x, cell_z, data)
+                };
+                let present: HashSet<_> = existing.iter().map(|(object, _)| identity(object)).collect();
+                // The bytes are copied as they are, as not every byte survives decoding and encoding again
+                let mut new_data = Vec::with_capacity(data.len());
+                for (object, range) in parse_objects(data, cell_x, cell_z, &self.code_page)? {
+                    if !present.contains(&identity(&object)) {
+                        new_data.extend_from_slice(&data[range]);
+                    }
+                }
+                if new_data.is_empty() {
+                    return Ok(());
+                }
+                self.cache.write_cell(cell_x, cell_z, &new_data)
+            }
+        }
+    }
+}
+
+/// A record of cell.dat, along with the key it was found under
+#[derive(Debug, Clone)]
+pub struct Cell {
+    pub key: CellKey,
+    pub data: Vec<u8>
+}
+
+impl Cell {
+    /// Parses the objects of a `CELL_DATA` record
+    pub fn objects(&self, code_page: &CodePage) -> Result<Vec<Object>, failure::Error> {
+        ensure!(self.key.kind == CELL_DATA, "Not a cell data record: {:?}", self.key);
+        Ok(parse_objects(&self.data, self.key.cell_x, self.key.cell_z, code_page)?.into_iter().map(|(object, _)| object).collect())
+    }
+}
+
+/// Walks cell.idx in key order, reading each record from cell.dat
+#[derive(Debug)]
+pub struct CellReader<'idx, 'dat> {
+    keys: ctree::Keys<'idx>,
+    dat: &'dat DatFile
+}
+
+impl<'idx, 'dat> CellReader<'idx, 'dat> {
+    pub fn new(idx: &'idx IdxFile, dat: &'dat DatFile) -> Self {
+        CellReader {
+            keys: ctree::keys(idx),
+            dat
+        }
+    }
+
+    /// Every object in the cache, in cell order
+    pub fn objects<'a>(self, code_page: CodePage) -> impl Iterator<Item=Result<Object, failure::Error>> + 'a where 'idx: 'a, 'dat: 'a {
+        self.filter(|cell| cell.as_ref().map(|cell| cell.key.kind == CELL_DATA).unwrap_or(true))
+            .flat_map(move |cell| {
+                let objects: Vec<Result<Object, failure::Error>> = match cell.and_then(|cell| cell.objects(&code_page)) {
+                    Ok(objects) => objects.into_iter().map(Ok).collect(),
+                    Err(err) => vec![Err(err)]
+                };
+                objects
+            })
+    }
+}
+
+impl<'idx, 'dat> Iterator for CellReader<'idx, 'dat> {
+    type Item = Result<Cell, failure::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, addr) = match self.keys.next()? {
+            Ok(entry) => entry,
+            Err(err) => return Some(Err(err.into()))
+        };
+        let key = match CellKey::from_bytes(&key) {
+            Some(key) => key,
+            None => return Some(Err(format_err!("Unexpected key length {} in cell index", key.len())))
+        };
+        Some(ctree::read(self.dat, &addr).map(|data| Cell { key, data }).map_err(failure::Error::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+    use std::slice;
+
+    use super::*;
+
+    /// A cache made from the templates in the temporary directory, removed again when dropped
+    struct Scratch(String);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let scratch = Scratch(env::temp_dir().join(format!("propdump2cell42-{}-{}", name, process::id())).to_str().unwrap().to_string());
+            drop(Cache::create(&scratch.0).unwrap());
+            scratch
+        }
+
+        fn open(&self) -> Cache {
+            Cache::open(&self.0).unwrap()
+        }
+
+        fn dat_len(&self) -> u64 {
+            fs::metadata(format!("{}.dat", self.0)).unwrap().len()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(format!("{}.idx", self.0));
+            let _ = fs::remove_file(format!("{}.dat", self.0));
+        }
+    }
+
+    fn object(citnum: i32, x: i32, z: i32, name: &str) -> Object {
+        Object {
+            citnum,
+            time: 1000,
+            x,
+            z,
+            name: name.to_string(),
+            action: format!("create name n{}", citnum),
+            ..Object::default()
+        }
+    }
+
+    fn encode(objects: &[Object]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for object in objects {
+            object.write_encoded(&mut data, &object.encode_text(&CodePage::default())).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn blank_size_matches_the_template() {
+        assert_eq!(BLANK_DAT_SIZE, include_bytes!("../blank42.dat").len() as u64);
+    }
+
+    #[test]
+    fn dry_runs_estimate_the_size_of_the_cache() {
+        let scratch = Scratch::new("dry-run");
+        let mut cache = scratch.open();
+        let mut dry_run = DryRun::default();
+        for cell in 0..40 {
+            let objects: Vec<Object> = (0..cell % 5 + 40).map(|n| object(n, cell * 1000 - 20000, -cell * 1000, &"model.rwx"[..3 + (n % 6) as usize])).collect();
+            for object in &objects {
+                let loc = object.location();
+                dry_run.add(loc.cell_x, loc.cell_z, encode(slice::from_ref(object)).len());
+            }
+            let loc = objects[0].location();
+            cache.write_cell(loc.cell_x, loc.cell_z, &encode(&objects)).unwrap();
+        }
+        drop(cache);
+        assert_eq!(dry_run.cells(), 40);
+        // The estimate counts the whole template, though the first records go into space it has free
+        let (estimate, len) = (estimate_dat_size(dry_run.cells(), dry_run.bytes()), scratch.dat_len());
+        assert!(estimate >= len && estimate - len < BLANK_DAT_SIZE, "estimated {}, got {}", estimate, len);
+    }
+
+    /// The data of a cell in a cache
+    fn cell_data(cache: &Cache, cell: (i16, i16)) -> Option<Vec<u8>> {
+        ctree::get(&cache.idx, &cache.dat, &CellKey::new(CELL_DATA, cell.0, cell.1).to_bytes()).unwrap()
+    }
+
+    /// A cache with objects `a` and `b` in cell 0 0, and `a` in cell 1 0
+    fn existing(name: &str) -> (Scratch, Vec<u8>, Vec<u8>) {
+        let scratch = Scratch::new(name);
+        let (a, b) = (encode(&[object(1, 0, 0, "a.rwx")]), encode(&[object(2, 0, 0, "b.rwx")]));
+        let mut cache = scratch.open();
+        cache.write_cell(0, 0, &[&a[..], &b[..]].concat()).unwrap();
+        cache.write_cell(1, 0, &encode(&[object(1, 1000, 0, "a.rwx")])).unwrap();
+        (scratch, a, b)
+    }
+
+    #[test]
+    fn appending_adds_to_cells() {
+        let (scratch, a, b) = existing("append");
+        let c = encode(&[object(3, 0, 0, "c.rwx")]);
+        let mut cache = UpdatedCache::new(scratch.open(), UpdateMode::Append, CodePage::default());
+        cache.write_cell(0, 0, &a).unwrap();
+        cache.write_cell(0, 0, &c).unwrap();
+        cache.write_cell(2, 0, &c).unwrap();
+        assert_eq!(cell_data(&cache.cache, (0, 0)), Some([&a[..], &b[..], &a[..], &c[..]].concat()));
+        assert_eq!(cell_data(&cache.cache, (2, 0)), Some(c));
+    }
+
+    #[test]
+    fn replacing_drops_what_cells_had_before_the_run() {
+        let (scratch, a, _) = existing("replace");
+        let c = encode(&[object(3, 0, 0, "c.rwx")]);
+        let mut cache = UpdatedCache::new(scratch.open(), UpdateMode::Replace, CodePage::default());
+        cache.write_cell(0, 0, &c).unwrap();
+        cache.write_cell(0, 0, &a).unwrap();
+        assert_eq!(cell_data(&cache.cache, (0, 0)), Some([&c[..], &a[..]].concat()));
+        assert_eq!(cell_data(&cache.cache, (1, 0)), Some(encode(&[object(1, 1000, 0, "a.rwx")])));
+    }
+
+    #[test]
+    fn deduplicating_adds_only_new_objects_as_they_were() {
+        let (scratch, a, b) = existing("dedupe");
+        // A byte windows-1252 has no character for, which would come back as '?' if decoded and encoded again
+        let mut c = encode(&[object(3, 0, 0, "c.rwx")]);
+        let name = c.windows(5).position(|window| window == b"c.rwx").unwrap();
+        c[name] = 0x81;
+        let mut cache = UpdatedCache::new(scratch.open(), UpdateMode::Dedupe, CodePage::default());
+        cache.write_cell(0, 0, &[&b[..], &c[..], &a[..]].concat()).unwrap();
+        cache.write_cell(1, 0, &encode(&[object(1, 1000, 0, "a.rwx")])).unwrap();
+        cache.write_cell(2, 0, &c).unwrap();
+        assert_eq!(cell_data(&cache.cache, (0, 0)), Some([&a[..], &b[..], &c[..]].concat()));
+        assert_eq!(cell_data(&cache.cache, (1, 0)), Some(encode(&[object(1, 1000, 0, "a.rwx")])));
+        assert_eq!(cell_data(&cache.cache, (2, 0)), Some(c));
+    }
+
+    #[test]
+    fn objects_are_written_out_as_propdumps() {
+        let (scratch, _, _) = existing("to-propdump");
+        let mut special = object(4, 1500, -200, "é.rwx");
+        special.type_ = 2;
+        special.desc = "two\r\nlines".to_string();
+        special.data = vec![0x01, 0xAB];
+        scratch.open().write_cell(1, -1, &encode(&[special])).unwrap();
+        let cache = scratch.open();
+        let written = |version| {
+            let mut dump = Vec::new();
+            {
+                let mut out = PropdumpWriter::new(&mut dump, version).unwrap();
+                cache.write_propdump(&mut out, CodePage::default()).unwrap();
+                out.flush().unwrap();
+            }
+            dump
+        };
+        assert_eq!(written(3), &b"propdump version 3\r\n\
+            1 1000 0 0 0 0 0 0 5 0 14 a.rwxcreate name n1\r\n\
+            2 1000 0 0 0 0 0 0 5 0 14 b.rwxcreate name n2\r\n\
+            1 1000 1000 0 0 0 0 0 5 0 14 a.rwxcreate name n1\r\n\
+            4 1000 1500 0 -200 0 0 0 5 10 14 \xE9.rwxtwo\x80\x7Flinescreate name n4\r\n"[..]);
+        assert_eq!(written(4), &b"propdump version 4\r\n\
+            1 1000 0 0 0 0 0 0 0 5 0 14 0 a.rwxcreate name n1\r\n\
+            2 1000 0 0 0 0 0 0 0 5 0 14 0 b.rwxcreate name n2\r\n\
+            1 1000 1000 0 0 0 0 0 0 5 0 14 0 a.rwxcreate name n1\r\n\
+            4 1000 1500 0 -200 0 0 0 2 5 10 14 2 \xE9.rwxtwo\x80\x7Flinescreate name n401AB\r\n"[..]);
+    }
+}
@@ -0,0 +1,229 @@
+use failure;
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::mem;
+use std::path::PathBuf;
+
+/// Rough bookkeeping cost of a buffered cell on top of its data
+const CELL_OVERHEAD: usize = 64;
+/// Most run files open at once while merging
+const MAX_MERGE: usize = 64;
+
+/// Groups cell data by cell, whatever order it arrives in.
+///
+/// Cells are kept in memory until they take up more than the budget. Then they are written, in cell order,
+/// to a run file in the directory given. At the end the runs are merged, so every cell comes out exactly once
+/// with its data in arrival order.
+pub struct CellSorter {
+    cells: BTreeMap<(i16, i16), Vec<u8>>,
+    buffered: usize,
+    budget: usize,
+    /// Where run files go
+    dir: PathBuf,
+    /// Run files not yet merged, oldest first
+    runs: Vec<PathBuf>,
+    next_run: usize
+}
+
+impl CellSorter {
+    pub fn new<P: Into<PathBuf>>(dir: P, budget: usize) -> Self {
+        CellSorter {
+            cells: BTreeMap::new(),
+            buffered: 0,
+            budget,
+            dir: dir.into(),
+            runs: Vec::new(),
+            next_run: 0
+        }
+    }
+
+    pub fn push(&mut self, cell: (i16, i16), data: &[u8]) -> Result<(), failure::Error> {
+        let buffered = &mut self.buffered;
+        let buffer = self.cells.entry(cell).or_insert_with(|| {
+            *buffered += CELL_OVERHEAD;
+            Vec::new()
+        });
+        buffer.extend_from_slice(data);
+        self.buffered += data.len();
+        if self.buffered > self.budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn new_run(&mut self) -> Result<BufWriter<File>, failure::Error> {
+        let name = self.dir.join(format!("cell.sort.{}.tmp", self.next_run));
+        self.next_run += 1;
+        let run = BufWriter::new(File::create(&name)?);
+        self.runs.push(name);
+        Ok(run)
+    }
+
+    fn spill(&mut self) -> Result<(), failure::Error> {
+        let mut run = self.new_run()?;
+        for ((cell_x, cell_z), data) in mem::take(&mut self.cells) {
+            write_entry(&mut run, (cell_x, cell_z), &data)?;
+        }
+        run.flush()?;
+        self.buffered = 0;
+        Ok(())
+    }
+
+    /// Hands every cell to `write`, in cell order
+    pub fn finish<F>(mut self, mut write: F) -> Result<(), failure::Error>
+        where F: FnMut(i16, i16, &[u8]) -> Result<(), failure::Error> {
+        if self.runs.is_empty() {
+            for ((cell_x, cell_z), data) in mem::take(&mut self.cells) {
+                write(cell_x, cell_z, &data)?;
+            }
+            return Ok(());
+        }
+        if !self.cells.is_empty() {
+            self.spill()?;
+        }
+        // Keep the number of open files down by merging neighbouring runs first. Merged runs go to the back,
+        // and each group stays in `runs` until its merged run is written, so none is left behind on failure.
+        while self.runs.len() > MAX_MERGE {
+            let mut left = self.runs.len();
+            while left > 0 {
+                let group = self.runs[..left.min(MAX_MERGE)].to_vec();
+                let mut run = self.new_run()?;
+                merge(&group, |cell, data| write_entry(&mut run, cell, data))?;
+                run.flush()?;
+                for name in &group {
+                    fs::remove_file(name)?;
+                }
+                self.runs.drain(..group.len());
+                left -= group.len();
+            }
+        }
+        merge(&self.runs, |(cell_x, cell_z), data| write(cell_x, cell_z, data))
+    }
+}
+
+impl Drop for CellSorter {
+    fn drop(&mut self) {
+        for name in &self.runs {
+            let _ = fs::remove_file(name);
+        }
+    }
+}
+
+/// A cell and its data, as stored in a run file
+type RunEntry = ((i16, i16), Vec<u8>);
+
+fn write_entry<W: Write>(w: &mut W, cell: (i16, i16), data: &[u8]) -> Result<(), failure::Error> {
+    w.write_i16::<LE>(cell.0)?;
+    w.write_i16::<LE>(cell.1)?;
+    w.write_u32::<LE>(data.len() as u32)?;
+    w.write_all(data)?;
+    Ok(())
+}
+
+/// Merges runs into a single stream of cells in cell order. The data of a cell found in several runs is
+/// concatenated in run order.
+fn merge<F>(names: &[PathBuf], mut write: F) -> Result<(), failure::Error>
+    where F: FnMut((i16, i16), &[u8]) -> Result<(), failure::Error> {
+    let mut runs = Vec::with_capacity(names.len());
+    let mut heads = BinaryHeap::new();
+    for (index, name) in names.iter().enumerate() {
+        let mut run = RunReader(BufReader::new(File::open(name)?));
+        if let Some((cell, data)) = run.next()? {
+            heads.push(Reverse((cell, index, data)));
+        }
+        runs.push(run);
+    }
+    // Ties on the cell pop in run order
+    while let Some(Reverse((cell, index, mut data))) = heads.pop() {
+        if let Some((next, more)) = runs[index].next()? {
+            heads.push(Reverse((next, index, more)));
+        }
+        while heads.peek().map(|head| (head.0).0) == Some(cell) {
+            let Reverse((_, index, more)) = heads.pop().unwrap();
+            data.extend_from_slice(&more);
+            if let Some((next, more)) = runs[index].next()? {
+                heads.push(Reverse((next, index, more)));
+            }
+        }
+        write(cell, &data)?;
+    }
+    Ok(())
+}
+
+struct RunReader(BufReader<File>);
+
+impl RunReader {
+    fn next(&mut self) -> Result<Option<RunEntry>, failure::Error> {
+        let cell_x = match self.0.read_i16::<LE>() {
+            Ok(cell_x) => cell_x,
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into())
+        };
+        let cell_z = self.0.read_i16::<LE>()?;
+        let mut data = vec![0; self.0.read_u32::<LE>()? as usize];
+        io::Read::read_exact(&mut self.0, &mut data)?;
+        Ok(Some(((cell_x, cell_z), data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    /// A directory of its own for run files, removed again when dropped
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("propdump2cell42-{}-{}", name, process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            Scratch(dir)
+        }
+
+        fn run_files(&self) -> usize {
+            fs::read_dir(&self.0).unwrap().count()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn many_runs_merge_in_arrival_order_and_are_removed() {
+        let scratch = Scratch::new("cellsort");
+        let mut sorter = CellSorter::new(&scratch.0, 0);
+        for n in 0..200u8 {
+            sorter.push((i16::from(n % 7) - 3, -i16::from(n % 5)), &[n]).unwrap();
+        }
+        assert!(sorter.runs.len() > MAX_MERGE);
+        assert_eq!(scratch.run_files(), sorter.runs.len());
+        let mut cells = Vec::new();
+        sorter.finish(|cell_x, cell_z, data| {
+            cells.push(((cell_x, cell_z), data.to_vec()));
+            Ok(())
+        }).unwrap();
+        let mut expected: BTreeMap<(i16, i16), Vec<u8>> = BTreeMap::new();
+        for n in 0..200u8 {
+            expected.entry((i16::from(n % 7) - 3, -i16::from(n % 5))).or_default().push(n);
+        }
+        assert_eq!(cells, expected.into_iter().collect::<Vec<_>>());
+        assert_eq!(scratch.run_files(), 0);
+
+        let mut sorter = CellSorter::new(&scratch.0, 0);
+        for n in 0..200u8 {
+            sorter.push((0, 0), &[n]).unwrap();
+        }
+        assert!(sorter.finish(|_, _, _| bail!("Failed to write")).is_err());
+        assert_eq!(scratch.run_files(), 0);
+    }
+}
@@ -0,0 +1,265 @@
+//! Text encodings for the cache and for propdumps. AW 4.2 reads cache text in the code page of the Windows system
+//! it runs on, Windows-1252 on western systems, and older propdumps are in the code page of the server that wrote
+//! them.
+
+use failure;
+
+use std::fmt;
+use std::str;
+
+use encoding::{DecoderTrap, EncoderTrap};
+use encoding::all::{UTF_8, WINDOWS_1251, WINDOWS_1252, WINDOWS_31J};
+use encoding::label::encoding_from_whatwg_label;
+use encoding::types::EncodingRef;
+
+/// ASCII spellings of the letters from U+00C0 to U+017F
+static LATIN: [&str; 192] = [
+    "A", "A", "A", "A", "A", "A", "AE", "C", "E", "E", "E", "E", "I", "I", "I", "I",
+    "D", "N", "O", "O", "O", "O", "O", "x", "O", "U", "U", "U", "U", "Y", "Th", "ss",
+    "a", "a", "a", "a", "a", "a", "ae", "c", "e", "e", "e", "e", "i", "i", "i", "i",
+    "d", "n", "o", "o", "o", "o", "o", "/", "o", "u", "u", "u", "u", "y", "th", "y",
+    "A", "a", "A", "a", "A", "a", "C", "c", "C", "c", "C", "c", "C", "c", "D", "d",
+    "D", "d", "E", "e", "E", "e", "E", "e", "E", "e", "E", "e", "G", "g", "G", "g",
+    "G", "g", "G", "g", "H", "h", "H", "h", "I", "i", "I", "i", "I", "i", "I", "i",
+    "I", "i", "IJ", "ij", "J", "j", "K", "k", "k", "L", "l", "L", "l", "L", "l", "L",
+    "l", "L", "l", "N", "n", "N", "n", "N", "n", "'n", "N", "n", "O", "o", "O", "o",
+    "O", "o", "OE", "oe", "R", "r", "R", "r", "R", "r", "S", "s", "S", "s", "S", "s",
+    "S", "s", "T", "t", "T", "t", "T", "t", "U", "u", "U", "u", "U", "u", "U", "u",
+    "U", "u", "U", "u", "W", "w", "Y", "y", "Y", "Z", "z", "Z", "z", "Z", "z", "s"
+];
+
+/// A stand-in for a character the code page lacks, if there is a common one
+fn transliterate(c: char) -> Option<&'static str> {
+    Some(match c {
+        '\u{C0}'..='\u{17F}' => LATIN[c as usize - 0xC0],
+        '\u{A0}' | '\u{2002}'..='\u{200A}' => " ",
+        '\u{200B}' | '\u{AD}' => "",
+        '\u{2010}'..='\u{2015}' | '\u{2212}' => "-",
+        '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}' | '\u{2032}' => "'",
+        '\u{201C}' | '\u{201D}' | '\u{201E}' | '\u{201F}' | '\u{2033}' => "\"",
+        '\u{2039}' => "<",
+        '\u{203A}' => ">",
+        '\u{AB}' => "<<",
+        '\u{BB}' => ">>",
+        '\u{2026}' => "...",
+        '\u{2022}' => "*",
+        '\u{2020}' | '\u{2021}' => "+",
+        '\u{20AC}' => "EUR",
+        '\u{A9}' => "(C)",
+        '\u{AE}' => "(R)",
+        '\u{2122}' => "(TM)",
+        _ => return None
+    })
+}
+
+/// Punctuation found in text in the single byte code pages
+const PUNCTUATION: &str = "\u{A0}«»–—‘’“”„…•€№°©®™·±§¶µ¡¿£¥¢´²³½¼¾";
+
+/// Finds an encoding by name, such as windows-1251 or shift_jis, as long as it leaves ASCII as it is
+pub fn lookup(label: &str) -> Result<EncodingRef, failure::Error> {
+    let encoding = match encoding_from_whatwg_label(label) {
+        Some(encoding) => encoding,
+        None => bail!("Unknown code page {}", label)
+    };
+    // Lengths, numbers, '?' and the rest of the file formats assume ASCII stays ASCII
+    ensure!(encoding.encode("?azAZ09", EncoderTrap::Strict).ok().as_deref() == Some(&b"?azAZ09"[..]),
+            "The {} code page cannot be used, as it does not leave ASCII as it is", label);
+    Ok(encoding)
+}
+
+/// The writing an encoding is for, which decides what text in it looks like
+#[derive(Copy, Clone)]
+enum Script {
+    Latin,
+    Cyrillic,
+    Japanese
+}
+
+/// Share of the characters outside ASCII that make sense for an encoding, when it is a likely one at all
+fn plausibility(encoding: EncodingRef, script: Script, sample: &[u8]) -> f64 {
+    let text: Vec<char> = match encoding.decode(sample, DecoderTrap::Strict) {
+        Ok(text) => text.chars().collect(),
+        Err(_) => return 0.0
+    };
+    let cyrillic = |c: char| ('\u{410}'..='\u{44F}').contains(&c) || c == 'Ё' || c == 'ё';
+    let latin = |c: char| ('\u{C0}'..='\u{FF}').contains(&c) && c != '×' && c != '÷' || "ŒœŠšŽžŸ".contains(c);
+    let (mut foreign, mut plausible) = (0, 0);
+    for (i, &c) in text.iter().enumerate() {
+        if c.is_ascii() {
+            continue;
+        }
+        foreign += 1;
+        let prev = if i > 0 { text[i - 1] } else { ' ' };
+        let next = text.get(i + 1).cloned().unwrap_or(' ');
+        let fits = match script {
+            // Accented letters sit among unaccented ones
+            Script::Latin => latin(c) && (prev.is_ascii_alphabetic() || next.is_ascii_alphabetic()) || PUNCTUATION.contains(c),
+            // Cyrillic words rarely have a single letter
+            Script::Cyrillic => cyrillic(c) && (cyrillic(prev) || cyrillic(next)) || PUNCTUATION.contains(c),
+            // Kana, kanji and full width forms
+            Script::Japanese => ('\u{3000}'..='\u{30FF}').contains(&c) || ('\u{4E00}'..='\u{9FFF}').contains(&c) || ('\u{FF01}'..='\u{FF5E}').contains(&c)
+        };
+        if fits {
+            plausible += 1;
+        }
+    }
+    if foreign == 0 { 1.0 } else { f64::from(plausible) / f64::from(foreign) }
+}
+
+/// Guesses the encoding of some text: UTF-8 if it is valid UTF-8, otherwise whichever of Windows-1252,
+/// Windows-1251 and Shift-JIS makes the most sense of the characters outside ASCII. Gives `None` for plain ASCII,
+/// or when none of them make much sense of it.
+pub fn detect(sample: &[u8]) -> Option<EncodingRef> {
+    if sample.is_ascii() {
+        return None;
+    }
+    if str::from_utf8(sample).is_ok() {
+        return Some(UTF_8);
+    }
+    let mut best = None;
+    let candidates: [(EncodingRef, Script); 3] = [(WINDOWS_1252, Script::Latin), (WINDOWS_1251, Script::Cyrillic), (WINDOWS_31J, Script::Japanese)];
+    for &(encoding, script) in &candidates {
+        let score = plausibility(encoding, script, sample);
+        if score >= 0.5 && best.is_none_or(|(_, best)| score > best) {
+            best = Some((encoding, score));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// How the text of objects is stored in the cache
+#[derive(Copy, Clone)]
+pub struct CodePage {
+    encoding: EncodingRef,
+    /// Whether to write characters the code page lacks as similar ones it has, instead of as '?'
+    transliterate: bool
+}
+
+impl Default for CodePage {
+    fn default() -> Self {
+        CodePage {
+            encoding: WINDOWS_1252,
+            transliterate: false
+        }
+    }
+}
+
+impl fmt::Debug for CodePage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CodePage").field("encoding", &self.name()).field("transliterate", &self.transliterate).finish()
+    }
+}
+
+impl CodePage {
+    /// A code page that writes the characters it lacks as '?'
+    pub fn plain(encoding: EncodingRef) -> Self {
+        CodePage {
+            encoding,
+            transliterate: false
+        }
+    }
+
+    /// Looks up a code page by name, such as windows-1251 or shift_jis
+    pub fn from_label(label: &str, transliterate: bool) -> Result<Self, failure::Error> {
+        Ok(CodePage {
+            encoding: lookup(label)?,
+            transliterate
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        self.encoding.whatwg_name().unwrap_or_else(|| self.encoding.name())
+    }
+
+    /// Encodes text, along with how many characters it lacked and wrote as '?'
+    pub fn encode(&self, text: &str) -> (Vec<u8>, usize) {
+        if let Ok(bytes) = self.encoding.encode(text, EncoderTrap::Strict) {
+            return (bytes, 0);
+        }
+        let mut bytes = Vec::with_capacity(text.len());
+        let mut lost = 0;
+        let mut buf = [0u8; 4];
+        for c in text.chars() {
+            if let Ok(encoded) = self.encoding.encode(c.encode_utf8(&mut buf), EncoderTrap::Strict) {
+                bytes.extend_from_slice(&encoded);
+                continue;
+            }
+            let replaced = transliterate(c).filter(|_| self.transliterate)
+                .and_then(|replacement| self.encoding.encode(replacement, EncoderTrap::Strict).ok());
+            match replaced {
+                Some(encoded) => bytes.extend_from_slice(&encoded),
+                None => {
+                    bytes.push(b'?');
+                    lost += 1;
+                }
+            }
+        }
+        (bytes, lost)
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> String {
+        self.encoding.decode(bytes, DecoderTrap::Replace).expect("Replacing decoder failed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding::all::WINDOWS_1251;
+
+    use super::*;
+
+    fn encode(encoding: EncodingRef, text: &str) -> Vec<u8> {
+        encoding.encode(text, EncoderTrap::Strict).unwrap()
+    }
+
+    fn detected(sample: &[u8]) -> Option<&'static str> {
+        detect(sample).map(|encoding| encoding.name())
+    }
+
+    #[test]
+    fn samples_are_told_apart() {
+        assert_eq!(detected("1 2 0 0 0 0 0 0 8 6 0 café.rwxcrème\n".as_bytes()), Some("utf-8"));
+        assert_eq!(detected(&encode(WINDOWS_1252, "1 2 0 0 0 0 0 0 8 6 0 café.rwxcrème brûlée “Noël”\n")), Some("windows-1252"));
+        assert_eq!(detected(&encode(WINDOWS_1251, "1 2 0 0 0 0 0 0 8 10 0 wall.rwxПривет мир\n")), Some("windows-1251"));
+        assert_eq!(detected(&encode(WINDOWS_31J, "1 2 0 0 0 0 0 0 8 14 0 sign.rwxこんにちは世界\n")), Some("windows-31j"));
+    }
+
+    #[test]
+    fn ascii_is_left_undecided() {
+        assert_eq!(detected(b"1 2 0 0 0 0 0 0 8 0 0 tree.rwx\n"), None);
+        assert_eq!(detected(b""), None);
+    }
+
+    #[test]
+    fn text_that_makes_no_sense_in_any_is_left_undecided() {
+        assert_eq!(detected(b"a\xD7 b\xF7 c"), None);
+    }
+
+    #[test]
+    fn plausibility_is_the_share_of_characters_that_fit() {
+        let cyrillic = encode(WINDOWS_1251, "Привет");
+        assert_eq!(plausibility(WINDOWS_1251, Script::Cyrillic, &cyrillic), 1.0);
+        assert_eq!(plausibility(WINDOWS_1252, Script::Latin, &cyrillic), 0.0);
+        assert_eq!(plausibility(WINDOWS_1252, Script::Latin, &encode(WINDOWS_1252, "naïve ×")), 0.5);
+        assert_eq!(plausibility(WINDOWS_1252, Script::Latin, b"plain"), 1.0);
+    }
+
+    #[test]
+    fn lacking_characters_are_transliterated_when_asked() {
+        assert_eq!(transliterate('ß'), Some("ss"));
+        assert_eq!(transliterate('€'), Some("EUR"));
+        assert_eq!(transliterate('字'), None);
+        let plain = CodePage::from_label("windows-1251", false).unwrap();
+        let close = CodePage::from_label("windows-1251", true).unwrap();
+        assert_eq!(plain.encode("Café naïve Да"), (encode(WINDOWS_1251, "Caf? na?ve Да"), 2));
+        assert_eq!(close.encode("Café naïve Да"), (encode(WINDOWS_1251, "Cafe naive Да"), 0));
+        assert_eq!(close.encode("字"), (b"?".to_vec(), 1));
+    }
+
+    #[test]
+    fn code_pages_must_leave_ascii_alone() {
+        assert!(lookup("shift_jis").is_ok());
+        assert!(lookup("utf-16le").is_err());
+        assert!(lookup("no-such-code-page").is_err());
+    }
+}
@@ -0,0 +1,221 @@
+//! Downgrades objects for the AW 4.2 browser, which knows nothing of zones, particle emitters, movers and cameras,
+//! nor of the action commands added after it.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use aw::Object;
+use objdata;
+
+// Both lists follow the object scripting reference in the help of the AW 4.2 browser: every trigger and command
+// it documents, and none added by later browsers, such as the `enter` and `exit` triggers.
+
+/// Action triggers AW 4.2 understands
+const TRIGGERS: [&str; 4] = ["create", "activate", "bump", "adone"];
+
+/// Action commands AW 4.2 understands
+const COMMANDS: [&str; 30] = [
+    "ambient", "animate", "astart", "astop", "camera", "color", "corona", "examine", "frame", "light", "media", "move",
+    "name", "noise", "opacity", "picture", "rotate", "say", "scale", "seq", "shear", "sign", "solid", "sound",
+    "teleport", "texture", "timer", "url", "visible", "warp"
+];
+
+/// Splits on `separator` outside double quotes, so that sign text and the like stay whole
+fn split_unquoted(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (index, c) in text.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(&text[start..index]);
+            start = index + c.len_utf8();
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// The first word of a trigger or command, lowercased
+fn keyword(text: &str) -> String {
+    text.split_whitespace().next().unwrap_or("").to_lowercase()
+}
+
+/// The action with the triggers and commands AW 4.2 does not understand left out, along with their names.
+/// Commands are named by the word after the trigger and after each comma.
+fn strip_action(action: &str) -> (String, Vec<String>) {
+    let mut stripped = Vec::new();
+    let mut triggers = Vec::new();
+    for trigger in split_unquoted(action, ';') {
+        let trigger = trigger.trim();
+        if trigger.is_empty() {
+            continue;
+        }
+        let (name, body) = trigger.split_at(trigger.find(char::is_whitespace).unwrap_or(trigger.len()));
+        if !TRIGGERS.contains(&name.to_lowercase().as_str()) {
+            stripped.push(name.to_lowercase());
+            continue;
+        }
+        let commands: Vec<&str> = split_unquoted(body, ',').into_iter()
+            .map(str::trim)
+            .filter(|command| !command.is_empty())
+            .filter(|command| {
+                let name = keyword(command);
+                let known = COMMANDS.contains(&name.as_str());
+                if !known {
+                    stripped.push(name);
+                }
+                known
+            })
+            .collect();
+        if !commands.is_empty() {
+            triggers.push(format!("{} {}", name, commands.join(", ")));
+        }
+    }
+    (triggers.join("; "), stripped)
+}
+
+/// Makes objects fit for AW 4.2: objects of the types it cannot show are dropped, or replaced by a marker model,
+/// objects of unknown types become plain objects, and actions lose the commands it does not understand.
+/// Counts what was changed for a summary.
+#[derive(Debug, Clone)]
+pub struct Downgrade {
+    /// Model to put in place of zones, particle emitters, movers and cameras, instead of dropping them
+    marker: Option<String>,
+    quiet: bool,
+    /// Objects dropped or replaced by a marker, by type
+    replaced: BTreeMap<i32, u64>,
+    pub dropped: u64,
+    /// Objects of unknown types whose type and data were cleared
+    cleared: u64,
+    /// Actions that lost triggers or commands, and how often each of those was stripped
+    actions: u64,
+    commands: BTreeMap<String, u64>
+}
+
+impl Downgrade {
+    pub fn new(marker: Option<String>) -> Self {
+        Downgrade {
+            marker,
+            quiet: false,
+            replaced: BTreeMap::new(),
+            dropped: 0,
+            cleared: 0,
+            actions: 0,
+            commands: BTreeMap::new()
+        }
+    }
+
+    /// Downgrades without a summary, for measuring cells against a budget before the conversion that reports
+    pub fn quiet(&self) -> Self {
+        Downgrade {
+            quiet: true,
+            ..Downgrade::new(self.marker.clone())
+        }
+    }
+
+    /// The object as AW 4.2 can show it, or `None` if it is to be left out
+    pub fn apply<'a>(&mut self, object: &'a Object) -> Option<Cow<'a, Object>> {
+        let mut object = Cow::Borrowed(object);
+        match object.type_ {
+            objdata::TYPE_ZONE | objdata::TYPE_PARTICLES | objdata::TYPE_MOVER | objdata::TYPE_CAMERA => {
+                *self.replaced.entry(object.type_).or_insert(0) += 1;
+                let marker = match self.marker {
+                    Some(ref marker) => marker,
+                    None => {
+                        self.dropped += 1;
+                        return None;
+                    }
+                };
+                let object = object.to_mut();
+                object.type_ = objdata::TYPE_V3;
+                object.name = marker.clone();
+                object.action.clear();
+                object.data.clear();
+            },
+            objdata::TYPE_UNKNOWN | objdata::TYPE_V3 | objdata::TYPE_V4 => (),
+            _ => {
+                self.cleared += 1;
+                let object = object.to_mut();
+                object.type_ = objdata::TYPE_V4;
+                object.data.clear();
+            }
+        }
+        let (action, stripped) = strip_action(&object.action);
+        if !stripped.is_empty() {
+            self.actions += 1;
+            for command in stripped {
+                *self.commands.entry(command).or_insert(0) += 1;
+            }
+            object.to_mut().action = action;
+        }
+        Some(object)
+    }
+
+    /// Summarizes what was changed, if anything
+    pub fn report(&self) {
+        if self.quiet || (self.replaced.is_empty() && self.cleared == 0 && self.actions == 0) {
+            return;
+        }
+        eprintln!("Downgraded for AW 4.2:");
+        let fate = match self.marker {
+            Some(ref marker) => format!("replaced by {}", marker),
+            None => "dropped".to_string()
+        };
+        for (type_, objects) in &self.replaced {
+            eprintln!("    {} {} objects {}", objects, objdata::type_name(*type_).unwrap_or("unknown"), fate);
+        }
+        if self.cleared > 0 {
+            eprintln!("    {} objects of types AW 4.2 does not know made plain objects", self.cleared);
+        }
+        if self.actions > 0 {
+            let commands = self.commands.iter().map(|(command, count)| format!("{} {}", command, count)).collect::<Vec<_>>().join(", ");
+            eprintln!("    {} actions stripped of triggers and commands AW 4.2 does not know: {}", self.actions, commands);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_actions_are_left_alone() {
+        assert_eq!(strip_action("create color red, sign \"a; b, c\"; activate url http://example.com"),
+            ("create color red, sign \"a; b, c\"; activate url http://example.com".to_string(), vec![]));
+    }
+
+    #[test]
+    fn quoted_separators_stay_inside_their_command() {
+        assert_eq!(strip_action("create sign \"one, two; three\", glow; bump say \"hi; there\""),
+            ("create sign \"one, two; three\"; bump say \"hi; there\"".to_string(), vec!["glow".to_string()]));
+    }
+
+    #[test]
+    fn triggers_and_commands_match_in_any_case() {
+        assert_eq!(strip_action("Create Color red, PARTICLE x; ACTIVATE Teleport 1N 1W"),
+            ("Create Color red; ACTIVATE Teleport 1N 1W".to_string(), vec!["particle".to_string()]));
+    }
+
+    #[test]
+    fn unknown_triggers_are_dropped_whole() {
+        assert_eq!(strip_action("create color red; Enter teleport 1n 1w; exit say bye"),
+            ("create color red".to_string(), vec!["enter".to_string(), "exit".to_string()]));
+    }
+
+    #[test]
+    fn actions_can_end_up_empty() {
+        assert_eq!(strip_action("create particle x, light2 y; at 10 color red"),
+            (String::new(), vec!["particle".to_string(), "light2".to_string(), "at".to_string()]));
+        assert_eq!(strip_action(""), (String::new(), vec![]));
+    }
+
+    #[test]
+    fn emptied_actions_are_cleared_on_the_object() {
+        let mut downgrade = Downgrade::new(None);
+        let object = Object { action: "create particle x".to_string(), ..Object::default() };
+        assert_eq!(downgrade.apply(&object).unwrap().action, "");
+        assert_eq!(downgrade.actions, 1);
+    }
+}
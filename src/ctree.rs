@@ -1,31 +1,27 @@
-#![allow(non_upper_case_globals)]
-
-extern crate libloading as lib;
-
-use std::os::raw::c_char;
-
-use std::ffi::CString;
+//! Access to the c-tree files AW 4.2 uses for its cache.
+//!
+//! By default the subset of the c-tree file format that the cache needs is implemented natively.
+//! Building with the `ctreestd` feature goes through ctreestd.dll instead, which only works on 32-bit Windows.
 
 use std::fmt;
 use std::error;
 
+#[cfg(feature = "ctreestd")]
+mod dll;
+#[cfg(feature = "ctreestd")]
+pub use self::dll::{init, DatFile, IdxFile};
 
-lazy_static! {
-    static ref CT: lib::Library = lib::Library::new("ctreestd.dll").unwrap();
-    static ref InitCTree: lib::Symbol<'static, unsafe extern "C" fn(i16, i16, i16) -> i16> = unsafe { CT.get(b"_INTREE\0").unwrap() };
-    static ref AvailableFileNbr: lib::Symbol<'static, unsafe extern "C" fn(i16) -> i16> = unsafe { CT.get(b"_AVLFILNUM\0").unwrap() };
-    static ref OpenCtFile: lib::Symbol<'static, unsafe extern "C" fn(i16, *const c_char, i16) -> i16> = unsafe { CT.get(b"_OPNFIL\0").unwrap() };
-    static ref CloseCtFile: lib::Symbol<'static, unsafe extern "C" fn(i16, i16) -> i16> = unsafe { CT.get(b"_CLSFIL\0").unwrap() };
-    static ref AddKey: lib::Symbol<'static, unsafe extern "C" fn(i16, *const u8, i32, i16) -> i16> = unsafe { CT.get(b"_ADDKEY\0").unwrap() };
-    static ref NewVData: lib::Symbol<'static, unsafe extern "C" fn(i16, i32) -> i32> = unsafe { CT.get(b"_NEWVREC\0").unwrap() };
-    static ref WriteVData: lib::Symbol<'static, unsafe extern "C" fn(i16, i32, *const u8, i32) -> i16> = unsafe { CT.get(b"_WRTVREC\0").unwrap() };
-    static ref GetKey: lib::Symbol<'static, unsafe extern "C" fn(i16, *const u8) -> i32> = unsafe { CT.get(b"_EQLKEY\0").unwrap() };
-    static ref DeleteKey: lib::Symbol<'static, unsafe extern "C" fn(i16, *const u8, i32) -> i16> = unsafe { CT.get(b"_DELCHK\0").unwrap() };
-    static ref ReadVData: lib::Symbol<'static, unsafe extern "C" fn(i16, i32, *mut u8, i32) -> i16> = unsafe { CT.get(b"_RDVREC\0").unwrap() };
-    static ref VDataLength: lib::Symbol<'static, unsafe extern "C" fn(i16, i32) -> i32> = unsafe { CT.get(b"_GTVLEN\0").unwrap() };
-    static ref ReleaseVData: lib::Symbol<'static, unsafe extern "C" fn(i16, i32) -> i16> = unsafe { CT.get(b"_RETVREC\0").unwrap() };
-    static ref GetCtFileInfo: lib::Symbol<'static, unsafe extern "C" fn(i16, i16) -> i32> = unsafe { CT.get(b"_GETFIL\0").unwrap() };
-}
+// Also built alongside the DLL in tests, to compare the two
+#[cfg(any(not(feature = "ctreestd"), test))]
+#[cfg_attr(feature = "ctreestd", allow(dead_code))]
+mod native;
+#[cfg(not(feature = "ctreestd"))]
+pub use self::native::{init, DatFile, IdxFile};
+
+/// Data file records may not start past this, which keeps cell.dat under the 2GB AW limit
+pub const DAT_LIMIT: i32 = i32::MAX - 22000;
+/// Bytes a data file record takes up besides its data
+pub const RECORD_OVERHEAD: u64 = 10;
 
 #[derive(Copy, Clone, Debug)]
 pub enum Error {
@@ -50,163 +46,93 @@ impl error::Error for Error {
     }
 }
 
-fn error(errcode: i16) -> Result<(), Error> {
-    if errcode == 0 {
-        Ok(())
-    } else {
-        Err(Error::CTree(errcode))
-    }
-}
-
-pub fn init() -> Result<(), Error> {
-    error(unsafe {
-        InitCTree(3, 2, 32)
-    })
-}
-
 #[derive(Copy, Clone, Debug)]
 pub struct DatAddr(i32);
 
+/// Iterates over every key of an index in ascending order, along with the data address it points to
 #[derive(Debug)]
-pub struct DatFile(i16);
-#[derive(Debug)]
-pub struct IdxFile(i16, usize);
-
-impl DatFile {
-    pub fn open<S: Into<Vec<u8>>>(filename: S) -> Result<Self, Error> {
-        let filenum = unsafe { AvailableFileNbr(1) };
-        if filenum == -1 {
-            return Err(Error::CTree(-1));
-        }
-        let filename = CString::new(filename).unwrap();
-        let result = unsafe { OpenCtFile(filenum, filename.as_ptr(), 0) };
-        error(result).map(|_| DatFile(filenum))
-    }
-    
-    fn new_v_data(&self, len: i32) -> Result<DatAddr, Error> {
-        let result = unsafe {
-            NewVData(self.0, len)
-        };
-        if result == 0 || result > (i32::max_value() - 22000) {
-            if result != 0 {
-                let _ = self.release_v_data(&DatAddr(result));
-                Err(Error::OutOfSpace)
-            } else {
-                Err(Error::CTree(0))
-            }
-        } else {
-            Ok(DatAddr(result))
-        }
-    }
-    
-    fn write_v_data(&self, addr: &DatAddr, data: &[u8]) -> Result<(), Error> {
-        error(unsafe {
-            WriteVData(self.0, addr.0, data.as_ptr(), data.len() as i32)
-        })
-    }
-    
-    fn read_v_data(&self, addr: &DatAddr) -> Result<Vec<u8>, Error> {
-        unsafe {
-            let length = VDataLength(self.0, addr.0); 
-            let mut buffer = vec![0; length as usize];
-            error(ReadVData(self.0, addr.0, buffer.as_mut_ptr(), buffer.len() as i32)).map(|_| buffer)
-        }
-    }
-    
-    fn release_v_data(&self, addr: &DatAddr) -> Result<(), Error> {
-        unsafe {
-            error(ReleaseVData(self.0, addr.0))
-        }
-    }
-    
-    
+pub struct Keys<'idx> {
+    idx: &'idx IdxFile,
+    last: Option<Vec<u8>>,
+    done: bool
 }
 
-impl Drop for DatFile {
-    fn drop(&mut self) {
-        error(unsafe {
-            CloseCtFile(self.0, 0)
-        }).expect("Unable to correctly close a DatFile!")
+pub fn keys<'idx>(idx: &'idx IdxFile) -> Keys<'idx> {
+    Keys {
+        idx,
+        last: None,
+        done: false
     }
 }
 
-impl IdxFile {
-    pub fn open<S: Into<Vec<u8>>>(filename: S) -> Result<Self, Error> {
-        let filenum = unsafe { AvailableFileNbr(1) };
-        if filenum == -1 {
-            return Err(Error::CTree(-1));
-        }
-        let filename = CString::new(filename).unwrap();
-        let result = unsafe { OpenCtFile(filenum, filename.as_ptr(), 0) };
-        error(result).map(|_| { 
-            let keylen = unsafe {
-                GetCtFileInfo(filenum, 1)
-            };
-            IdxFile(filenum, keylen as usize)
-        })
-    }
-    
-    fn check_key(&self, key: &[u8]) -> Result<(), Error> {
-        if key.len() == self.1 {
-            Ok(())
-        } else {
-            Err(Error::BadKeyLength)
+impl<'idx> Iterator for Keys<'idx> {
+    type Item = Result<(Vec<u8>, DatAddr), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
         }
-    }
-    
-    fn add_key(&self, key: &[u8], dataddr: &DatAddr) -> Result<(), Error> {
-        self.check_key(key)?;
-        error(unsafe {
-            AddKey(self.0, key.as_ptr(), dataddr.0, 0)
-        })
-    }
-    
-    fn get_key(&self, key: &[u8]) -> Option<DatAddr> {
-        self.check_key(key).unwrap();
-        let num_addr = unsafe {
-            GetKey(self.0, key.as_ptr())
+        let result = match self.last {
+            Some(ref last) => self.idx.next_key(last),
+            None => self.idx.first_key()
         };
-        if num_addr == 0 {
-            None
-        } else {
-            Some(DatAddr(num_addr))
+        match result {
+            Ok(Some((key, addr))) => {
+                self.last = Some(key.clone());
+                Some(Ok((key, addr)))
+            },
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
         }
     }
-    
-    fn delete_key(&self, key: &[u8], addr: &DatAddr) -> Result<(), Error> {
-        self.check_key(key)?;
-        error(unsafe {
-            DeleteKey(self.0, key.as_ptr(), addr.0)
-        })
-    }
 }
 
-impl Drop for IdxFile {
-    fn drop(&mut self) {
-        error(unsafe {
-            CloseCtFile(self.0, 0)
-        }).expect("Unable to correctly close an IdxFile!")
+pub fn read(dat: &DatFile, addr: &DatAddr) -> Result<Vec<u8>, Error> {
+    dat.read_v_data(addr)
+}
+
+pub fn get(idx: &IdxFile, dat: &DatFile, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    match idx.get_key(key)? {
+        Some(addr) => dat.read_v_data(&addr).map(Some),
+        None => Ok(None)
     }
 }
 
+/// Reads the data under `key` and removes it from the files
+pub fn take(idx: &IdxFile, dat: &DatFile, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    let addr = match idx.get_key(key)? {
+        Some(addr) => addr,
+        None => return Ok(None)
+    };
+    let data = dat.read_v_data(&addr)?;
+    idx.delete_key(key, &addr)?;
+    dat.release_v_data(&addr)?;
+    Ok(Some(data))
+}
 
+/// Leaves the files as they were if the data does not fit
 pub fn insert_or_append(idx: &IdxFile, dat: &DatFile, key: &[u8], data: &[u8]) -> Result<(), Error> {
     let addr = dat.new_v_data(data.len() as i32)?;
     dat.write_v_data(&addr, data)?;
     let add_key_result = idx.add_key(key, &addr);
     if let Err(Error::CTree(2)) = add_key_result {
         dat.release_v_data(&addr)?;
-        let old_addr = idx.get_key(key).ok_or(Error::CTree(0))?;
+        let old_addr = idx.get_key(key)?.ok_or(Error::CTree(0))?;
         let mut old_data = dat.read_v_data(&old_addr)?;
         old_data.extend_from_slice(data);
-        dat.release_v_data(&old_addr)?;
-        idx.delete_key(key, &old_addr)?;
         let addr = dat.new_v_data(old_data.len() as i32)?;
         dat.write_v_data(&addr, &old_data)?;
+        dat.release_v_data(&old_addr)?;
+        idx.delete_key(key, &old_addr)?;
         idx.add_key(key, &addr)?;
     } else {
         add_key_result?;
     }
     Ok(())
 }
-
@@ -0,0 +1,305 @@
+#![allow(non_upper_case_globals)]
+
+extern crate libloading as lib;
+
+use std::os::raw::c_char;
+
+use std::ffi::CString;
+
+use super::{Error, DatAddr, DAT_LIMIT};
+
+
+lazy_static! {
+    static ref CT: lib::Library = lib::Library::new("ctreestd.dll").unwrap();
+    static ref InitCTree: lib::Symbol<'static, unsafe extern "C" fn(i16, i16, i16) -> i16> = unsafe { CT.get(b"_INTREE\0").unwrap() };
+    static ref AvailableFileNbr: lib::Symbol<'static, unsafe extern "C" fn(i16) -> i16> = unsafe { CT.get(b"_AVLFILNUM\0").unwrap() };
+    static ref OpenCtFile: lib::Symbol<'static, unsafe extern "C" fn(i16, *const c_char, i16) -> i16> = unsafe { CT.get(b"_OPNFIL\0").unwrap() };
+    static ref CloseCtFile: lib::Symbol<'static, unsafe extern "C" fn(i16, i16) -> i16> = unsafe { CT.get(b"_CLSFIL\0").unwrap() };
+    static ref AddKey: lib::Symbol<'static, unsafe extern "C" fn(i16, *const u8, i32, i16) -> i16> = unsafe { CT.get(b"_ADDKEY\0").unwrap() };
+    static ref NewVData: lib::Symbol<'static, unsafe extern "C" fn(i16, i32) -> i32> = unsafe { CT.get(b"_NEWVREC\0").unwrap() };
+    static ref WriteVData: lib::Symbol<'static, unsafe extern "C" fn(i16, i32, *const u8, i32) -> i16> = unsafe { CT.get(b"_WRTVREC\0").unwrap() };
+    static ref GetKey: lib::Symbol<'static, unsafe extern "C" fn(i16, *const u8) -> i32> = unsafe { CT.get(b"_EQLKEY\0").unwrap() };
+    static ref FirstKey: lib::Symbol<'static, unsafe extern "C" fn(i16, *mut u8) -> i32> = unsafe { CT.get(b"_FRSKEY\0").unwrap() };
+    static ref GreaterKey: lib::Symbol<'static, unsafe extern "C" fn(i16, *const u8, *mut u8) -> i32> = unsafe { CT.get(b"_GTKEY\0").unwrap() };
+    static ref DeleteKey: lib::Symbol<'static, unsafe extern "C" fn(i16, *const u8, i32) -> i16> = unsafe { CT.get(b"_DELCHK\0").unwrap() };
+    static ref ReadVData: lib::Symbol<'static, unsafe extern "C" fn(i16, i32, *mut u8, i32) -> i16> = unsafe { CT.get(b"_RDVREC\0").unwrap() };
+    static ref VDataLength: lib::Symbol<'static, unsafe extern "C" fn(i16, i32) -> i32> = unsafe { CT.get(b"_GTVLEN\0").unwrap() };
+    static ref ReleaseVData: lib::Symbol<'static, unsafe extern "C" fn(i16, i32) -> i16> = unsafe { CT.get(b"_RETVREC\0").unwrap() };
+    static ref GetCtFileInfo: lib::Symbol<'static, unsafe extern "C" fn(i16, i16) -> i32> = unsafe { CT.get(b"_GETFIL\0").unwrap() };
+}
+
+fn error(errcode: i16) -> Result<(), Error> {
+    if errcode == 0 {
+        Ok(())
+    } else {
+        Err(Error::CTree(errcode))
+    }
+}
+
+pub fn init() -> Result<(), Error> {
+    error(unsafe {
+        InitCTree(3, 2, 32)
+    })
+}
+
+#[derive(Debug)]
+pub struct DatFile(i16);
+#[derive(Debug)]
+pub struct IdxFile(i16, usize);
+
+impl DatFile {
+    pub fn open<S: Into<Vec<u8>>>(filename: S) -> Result<Self, Error> {
+        let filenum = unsafe { AvailableFileNbr(1) };
+        if filenum == -1 {
+            return Err(Error::CTree(-1));
+        }
+        let filename = CString::new(filename).unwrap();
+        let result = unsafe { OpenCtFile(filenum, filename.as_ptr(), 0) };
+        error(result).map(|_| DatFile(filenum))
+    }
+    
+    pub(super) fn new_v_data(&self, len: i32) -> Result<DatAddr, Error> {
+        let result = unsafe {
+            NewVData(self.0, len)
+        };
+        if result == 0 || result > DAT_LIMIT {
+            if result != 0 {
+                let _ = self.release_v_data(&DatAddr(result));
+                Err(Error::OutOfSpace)
+            } else {
+                Err(Error::CTree(0))
+            }
+        } else {
+            Ok(DatAddr(result))
+        }
+    }
+    
+    pub(super) fn write_v_data(&self, addr: &DatAddr, data: &[u8]) -> Result<(), Error> {
+        error(unsafe {
+            WriteVData(self.0, addr.0, data.as_ptr(), data.len() as i32)
+        })
+    }
+    
+    pub(super) fn read_v_data(&self, addr: &DatAddr) -> Result<Vec<u8>, Error> {
+        unsafe {
+            let length = VDataLength(self.0, addr.0); 
+            let mut buffer = vec![0; length as usize];
+            error(ReadVData(self.0, addr.0, buffer.as_mut_ptr(), buffer.len() as i32)).map(|_| buffer)
+        }
+    }
+    
+    pub(super) fn release_v_data(&self, addr: &DatAddr) -> Result<(), Error> {
+        unsafe {
+            error(ReleaseVData(self.0, addr.0))
+        }
+    }
+    
+    
+}
+
+impl Drop for DatFile {
+    fn drop(&mut self) {
+        error(unsafe {
+            CloseCtFile(self.0, 0)
+        }).expect("Unable to correctly close a DatFile!")
+    }
+}
+
+impl IdxFile {
+    pub fn open<S: Into<Vec<u8>>>(filename: S) -> Result<Self, Error> {
+        let filenum = unsafe { AvailableFileNbr(1) };
+        if filenum == -1 {
+            return Err(Error::CTree(-1));
+        }
+        let filename = CString::new(filename).unwrap();
+        let result = unsafe { OpenCtFile(filenum, filename.as_ptr(), 0) };
+        error(result).map(|_| { 
+            let keylen = unsafe {
+                GetCtFileInfo(filenum, 1)
+            };
+            IdxFile(filenum, keylen as usize)
+        })
+    }
+    
+    fn check_key(&self, key: &[u8]) -> Result<(), Error> {
+        if key.len() == self.1 {
+            Ok(())
+        } else {
+            Err(Error::BadKeyLength)
+        }
+    }
+    
+    pub(super) fn add_key(&self, key: &[u8], dataddr: &DatAddr) -> Result<(), Error> {
+        self.check_key(key)?;
+        error(unsafe {
+            AddKey(self.0, key.as_ptr(), dataddr.0, 0)
+        })
+    }
+    
+    pub(super) fn get_key(&self, key: &[u8]) -> Result<Option<DatAddr>, Error> {
+        self.check_key(key)?;
+        let num_addr = unsafe {
+            GetKey(self.0, key.as_ptr())
+        };
+        if num_addr == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(DatAddr(num_addr)))
+        }
+    }
+    
+    pub(super) fn first_key(&self) -> Result<Option<(Vec<u8>, DatAddr)>, Error> {
+        let mut found = vec![0u8; self.1];
+        let num_addr = unsafe {
+            FirstKey(self.0, found.as_mut_ptr())
+        };
+        if num_addr == 0 {
+            Ok(None)
+        } else {
+            Ok(Some((found, DatAddr(num_addr))))
+        }
+    }
+    
+    pub(super) fn next_key(&self, key: &[u8]) -> Result<Option<(Vec<u8>, DatAddr)>, Error> {
+        self.check_key(key)?;
+        let mut found = vec![0u8; self.1];
+        let num_addr = unsafe {
+            GreaterKey(self.0, key.as_ptr(), found.as_mut_ptr())
+        };
+        if num_addr == 0 {
+            Ok(None)
+        } else {
+            Ok(Some((found, DatAddr(num_addr))))
+        }
+    }
+    
+    pub(super) fn delete_key(&self, key: &[u8], addr: &DatAddr) -> Result<(), Error> {
+        self.check_key(key)?;
+        error(unsafe {
+            DeleteKey(self.0, key.as_ptr(), addr.0)
+        })
+    }
+}
+
+impl Drop for IdxFile {
+    fn drop(&mut self) {
+        error(unsafe {
+            CloseCtFile(self.0, 0)
+        }).expect("Unable to correctly close an IdxFile!")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Once;
+    use byteorder::{ByteOrder, LE};
+
+    use super::*;
+    use ctree::native;
+
+    static INIT: Once = Once::new();
+
+    /// Copies of the templates, removed again when dropped
+    struct Scratch {
+        idx: PathBuf,
+        dat: PathBuf
+    }
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let base = env::temp_dir().join(format!("propdump2cell42-{}-{}", name, std::process::id()));
+            let scratch = Scratch {
+                idx: base.with_extension("idx"),
+                dat: base.with_extension("dat")
+            };
+            fs::copy("blank42.idx", &scratch.idx).unwrap();
+            fs::copy("blank42.dat", &scratch.dat).unwrap();
+            scratch
+        }
+
+        fn paths(&self) -> (&str, &str) {
+            (self.idx.to_str().unwrap(), self.dat.to_str().unwrap())
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.idx);
+            let _ = fs::remove_file(&self.dat);
+        }
+    }
+
+    fn key(n: u32) -> Vec<u8> {
+        let mut key = vec![0, 1, 0, 0, 0, 0];
+        LE::write_u32(&mut key[2..], n.swap_bytes());
+        key
+    }
+
+    fn data(n: u32) -> Vec<u8> {
+        (0..n % 700 + 1).map(|i| (n + i) as u8).collect()
+    }
+
+    /// Inserts enough records to split nodes on every level, deletes some of them,
+    /// then appends to others the way `ctree::insert_or_append` does.
+    /// A macro so that the same calls run against both implementations.
+    macro_rules! edit {
+        ($idx:expr, $dat:expr) => {{
+            let (idx, dat) = ($idx, $dat);
+            let insert = |n: u32, data: &[u8]| {
+                let addr = dat.new_v_data(data.len() as i32).unwrap();
+                dat.write_v_data(&addr, data).unwrap();
+                idx.add_key(&key(n), &addr)
+            };
+            for n in 0..3000 {
+                insert(n, &data(n)).unwrap();
+            }
+            for n in (0..3000).filter(|n| n % 3 == 0 || (1000..2000).contains(n)) {
+                let addr = idx.get_key(&key(n)).unwrap().unwrap();
+                idx.delete_key(&key(n), &addr).unwrap();
+                dat.release_v_data(&addr).unwrap();
+            }
+            for n in (0..3000).step_by(7) {
+                let addr = dat.new_v_data(data(n + 1).len() as i32).unwrap();
+                dat.write_v_data(&addr, &data(n + 1)).unwrap();
+                if idx.add_key(&key(n), &addr).is_err() {
+                    dat.release_v_data(&addr).unwrap();
+                    let old_addr = idx.get_key(&key(n)).unwrap().unwrap();
+                    let mut appended = dat.read_v_data(&old_addr).unwrap();
+                    appended.extend(data(n + 1));
+                    let addr = dat.new_v_data(appended.len() as i32).unwrap();
+                    dat.write_v_data(&addr, &appended).unwrap();
+                    dat.release_v_data(&old_addr).unwrap();
+                    idx.delete_key(&key(n), &old_addr).unwrap();
+                    idx.add_key(&key(n), &addr).unwrap();
+                }
+            }
+        }}
+    }
+
+    /// The offset of the first byte that differs, if any
+    fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+        a.iter().zip(b).position(|(a, b)| a != b).or_else(|| if a.len() == b.len() { None } else { Some(a.len().min(b.len())) })
+    }
+
+    #[test]
+    fn native_files_match_the_dll() {
+        INIT.call_once(|| init().unwrap());
+        let dll = Scratch::new("dll");
+        let ours = Scratch::new("native");
+        {
+            let (idx, dat) = dll.paths();
+            edit!(&IdxFile::open(idx).unwrap(), &DatFile::open(dat).unwrap());
+        }
+        {
+            let (idx, dat) = ours.paths();
+            edit!(&native::IdxFile::open(idx).unwrap(), &native::DatFile::open(dat).unwrap());
+        }
+        for (theirs, ours) in &[(&dll.idx, &ours.idx), (&dll.dat, &ours.dat)] {
+            let difference = first_difference(&fs::read(theirs).unwrap(), &fs::read(ours).unwrap());
+            assert_eq!(difference, None, "{} differs from what ctreestd.dll wrote at the given offset", ours.display());
+        }
+    }
+}
@@ -0,0 +1,789 @@
+//! Pure Rust implementation of the c-tree variable length data file and B-tree index formats,
+//! limited to what ctreestd.dll is used for by this program.
+//!
+//! Layout notes, from the blank42 templates:
+//! * Both files start with a header. Positions in the files are 32-bit byte offsets,
+//!   `phyrec` is the last byte of the file and `numrec` the last byte in use.
+//!   Files grow in `extsiz` steps, filled with 0xFF.
+//!   The header also counts the records in use of a data file and the keys of an index,
+//!   both 1 in the blanks for the version record.
+//! * Data records start after the first `node_size` bytes. Each is a 10 byte header (mark, total length
+//!   including the header, used length) followed by the record bytes. Released records get the deleted mark,
+//!   and their space is reused for new records, found again by walking the records when the file is opened.
+//! * Index nodes are `node_size` bytes, the first at `node_size`: right and left sibling, key count, key bytes, leaf flag,
+//!   then `(pointer, key)` entries in ascending byte order. Every node has a high key, the largest
+//!   key it may hold. Nonleaf entries point at a child and carry that child's high key, leaves
+//!   store their high key after the entry area. The rightmost node on each level has an all 0xFF high key.
+//!   Nodes emptied by deletes are unlinked, except for the rightmost ones, and reused for later splits.
+//!   Nodes that are not in the tree are found again when the index is opened.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use byteorder::{ByteOrder, LE};
+
+use super::{Error, DatAddr, DAT_LIMIT};
+
+const KDUP_ERR: i16 = 2;
+const KMAT_ERR: i16 = 3;
+const KDEL_ERR: i16 = 4;
+const FNOP_ERR: i16 = 12;
+const READ_ERR: i16 = 36;
+const WRITE_ERR: i16 = 37;
+const VLEN_ERR: i16 = 149;
+const VFLG_ERR: i16 = 158;
+
+const HEADER_LEN: usize = 128;
+const PHYREC: usize = 0x00;
+const NUMREC: usize = 0x08;
+const DAT_COUNT: usize = 0x14;
+const IDX_COUNT: usize = 0x18;
+const ROOT: usize = 0x1C;
+const NODE_SIZE: usize = 0x2A;
+const EXTSIZ: usize = 0x2E;
+const RECLEN: usize = 0x32;
+const MAXKBL: usize = 0x34;
+const MAXKBN: usize = 0x36;
+const KEYLEN: usize = 0x42;
+const MEMBER_ROOT: usize = 0x48;
+
+const VDATA_MARK: u16 = 0xFAFA;
+const VDEL_MARK: u16 = 0xFDFD;
+const VHDR_LEN: usize = 10;
+
+const NODE_HDR_LEN: usize = 18;
+const POINTER_LEN: usize = 4;
+
+pub fn init() -> Result<(), Error> {
+    Ok(())
+}
+
+#[derive(Debug)]
+struct CtFile {
+    file: File,
+    header: [u8; HEADER_LEN]
+}
+
+impl CtFile {
+    fn open<S: Into<Vec<u8>>>(filename: S) -> Result<Self, Error> {
+        let filename = String::from_utf8(filename.into()).map_err(|_| Error::CTree(FNOP_ERR))?;
+        let mut file = OpenOptions::new().read(true).write(true).open(filename).map_err(|_| Error::CTree(FNOP_ERR))?;
+        let mut header = [0u8; HEADER_LEN];
+        file.read_exact(&mut header).map_err(|_| Error::CTree(READ_ERR))?;
+        Ok(CtFile {
+            file,
+            header
+        })
+    }
+
+    fn get_u16(&self, pos: usize) -> u16 {
+        LE::read_u16(&self.header[pos..pos+2])
+    }
+
+    fn get_u32(&self, pos: usize) -> u32 {
+        LE::read_u32(&self.header[pos..pos+4])
+    }
+
+    fn set_u32(&mut self, pos: usize, value: u32) {
+        LE::write_u32(&mut self.header[pos..pos+4], value);
+    }
+
+    fn read_at(&mut self, pos: u32, buf: &mut [u8]) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(pos as u64)).map_err(|_| Error::CTree(READ_ERR))?;
+        self.file.read_exact(buf).map_err(|_| Error::CTree(READ_ERR))
+    }
+
+    fn write_at(&mut self, pos: u32, buf: &[u8]) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(pos as u64)).map_err(|_| Error::CTree(WRITE_ERR))?;
+        self.file.write_all(buf).map_err(|_| Error::CTree(WRITE_ERR))
+    }
+
+    fn write_header(&mut self) -> Result<(), Error> {
+        let header = self.header;
+        self.write_at(0, &header)
+    }
+
+    /// Adds `delta` to the count at `pos` and writes out the header
+    fn count(&mut self, pos: usize, delta: i32) -> Result<(), Error> {
+        let count = self.get_u32(pos).wrapping_add(delta as u32);
+        self.set_u32(pos, count);
+        self.write_header()
+    }
+
+    /// Position of the first record or node, after the header
+    fn first(&self) -> u32 {
+        u32::from(self.get_u16(NODE_SIZE))
+    }
+
+    /// Reserves `len` bytes at the end of the used area, growing the file when needed
+    fn allocate(&mut self, len: u32) -> Result<u32, Error> {
+        let pos = self.get_u32(NUMREC).checked_add(1).ok_or(Error::OutOfSpace)?;
+        let last = pos.checked_add(len - 1).ok_or(Error::OutOfSpace)?;
+        let mut phyrec = self.get_u32(PHYREC);
+        if last > phyrec {
+            let extsiz = u32::from(self.get_u16(EXTSIZ)).max(1);
+            let grow = (last - phyrec).div_ceil(extsiz) * extsiz;
+            let fill = vec![0xFFu8; grow as usize];
+            self.write_at(phyrec + 1, &fill)?;
+            phyrec += grow;
+            self.set_u32(PHYREC, phyrec);
+        }
+        self.set_u32(NUMREC, last);
+        self.write_header()?;
+        Ok(pos)
+    }
+}
+
+/// Released records of a data file, merged with their released neighbours
+#[derive(Debug, Default)]
+struct FreeSpace {
+    by_addr: BTreeMap<u32, u32>,
+    by_size: BTreeSet<(u32, u32)>
+}
+
+impl FreeSpace {
+    /// Returns the position and total length of the free record `addr` ended up in
+    fn insert(&mut self, mut addr: u32, mut total: u32) -> (u32, u32) {
+        if let Some((&prev_addr, &prev_total)) = self.by_addr.range(..addr).next_back() {
+            if prev_addr + prev_total == addr {
+                self.remove(prev_addr, prev_total);
+                addr = prev_addr;
+                total += prev_total;
+            }
+        }
+        if let Some(&next_total) = self.by_addr.get(&(addr + total)) {
+            self.remove(addr + total, next_total);
+            total += next_total;
+        }
+        self.by_addr.insert(addr, total);
+        self.by_size.insert((total, addr));
+        (addr, total)
+    }
+
+    fn remove(&mut self, addr: u32, total: u32) {
+        self.by_addr.remove(&addr);
+        self.by_size.remove(&(total, addr));
+    }
+
+    /// Takes the smallest free record of at least `total` bytes
+    fn take(&mut self, total: u32) -> Option<(u32, u32)> {
+        let (size, addr) = *self.by_size.range((total, 0)..).next()?;
+        self.remove(addr, size);
+        Some((addr, size))
+    }
+}
+
+#[derive(Debug)]
+pub struct DatFile {
+    file: RefCell<CtFile>,
+    free: RefCell<FreeSpace>
+}
+
+fn write_vhdr(file: &mut CtFile, pos: u32, mark: u16, total: u32) -> Result<(), Error> {
+    let mut vhdr = [0u8; VHDR_LEN];
+    LE::write_u16(&mut vhdr[0..2], mark);
+    LE::write_u32(&mut vhdr[2..6], total);
+    file.write_at(pos, &vhdr)
+}
+
+/// Walks the records of a data file to find the released ones
+fn find_free_space(file: &mut CtFile) -> Result<FreeSpace, Error> {
+    let mut free = FreeSpace::default();
+    let end = file.get_u32(NUMREC);
+    let mut pos = file.first();
+    let mut vhdr = [0u8; VHDR_LEN];
+    while pos < end {
+        file.read_at(pos, &mut vhdr)?;
+        let total = LE::read_u32(&vhdr[2..6]);
+        if total < VHDR_LEN as u32 || total - 1 > end - pos {
+            return Err(Error::CTree(READ_ERR));
+        }
+        match LE::read_u16(&vhdr[0..2]) {
+            VDATA_MARK => (),
+            VDEL_MARK => {
+                free.insert(pos, total);
+            },
+            _ => return Err(Error::CTree(VFLG_ERR))
+        }
+        pos += total;
+    }
+    Ok(free)
+}
+
+impl DatFile {
+    pub fn open<S: Into<Vec<u8>>>(filename: S) -> Result<Self, Error> {
+        let mut file = CtFile::open(filename)?;
+        let free = find_free_space(&mut file)?;
+        Ok(DatFile {
+            file: RefCell::new(file),
+            free: RefCell::new(free)
+        })
+    }
+
+    /// Returns the total and used length of the record at `addr`
+    fn record_header(&self, addr: &DatAddr) -> Result<(u32, u32), Error> {
+        let mut vhdr = [0u8; VHDR_LEN];
+        self.file.borrow_mut().read_at(addr.0 as u32, &mut vhdr)?;
+        if LE::read_u16(&vhdr[0..2]) != VDATA_MARK {
+            return Err(Error::CTree(VFLG_ERR));
+        }
+        Ok((LE::read_u32(&vhdr[2..6]), LE::read_u32(&vhdr[6..10])))
+    }
+
+    pub(super) fn new_v_data(&self, len: i32) -> Result<DatAddr, Error> {
+        let mut file = self.file.borrow_mut();
+        let mut free = self.free.borrow_mut();
+        let reclen = u32::from(file.get_u16(RECLEN));
+        let total = (len.max(0) as u32).max(reclen).checked_add(VHDR_LEN as u32).ok_or(Error::OutOfSpace)?;
+        if let Some((pos, size)) = free.take(total) {
+            let total = if size - total >= VHDR_LEN as u32 + reclen {
+                write_vhdr(&mut file, pos + total, VDEL_MARK, size - total)?;
+                free.insert(pos + total, size - total);
+                total
+            } else {
+                size
+            };
+            write_vhdr(&mut file, pos, VDATA_MARK, total)?;
+            file.count(DAT_COUNT, 1)?;
+            return Ok(DatAddr(pos as i32));
+        }
+        if file.get_u32(NUMREC) >= DAT_LIMIT as u32 {
+            return Err(Error::OutOfSpace);
+        }
+        let pos = file.allocate(total)?;
+        write_vhdr(&mut file, pos, VDATA_MARK, total)?;
+        file.count(DAT_COUNT, 1)?;
+        Ok(DatAddr(pos as i32))
+    }
+
+    pub(super) fn write_v_data(&self, addr: &DatAddr, data: &[u8]) -> Result<(), Error> {
+        let (total, _) = self.record_header(addr)?;
+        if data.len() > total as usize - VHDR_LEN {
+            return Err(Error::CTree(VLEN_ERR));
+        }
+        let mut used = [0u8; 4];
+        LE::write_u32(&mut used, data.len() as u32);
+        let mut file = self.file.borrow_mut();
+        file.write_at(addr.0 as u32 + 6, &used)?;
+        file.write_at(addr.0 as u32 + VHDR_LEN as u32, data)
+    }
+
+    pub(super) fn read_v_data(&self, addr: &DatAddr) -> Result<Vec<u8>, Error> {
+        let (_, used) = self.record_header(addr)?;
+        let mut buffer = vec![0; used as usize];
+        self.file.borrow_mut().read_at(addr.0 as u32 + VHDR_LEN as u32, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    pub(super) fn release_v_data(&self, addr: &DatAddr) -> Result<(), Error> {
+        let (total, _) = self.record_header(addr)?;
+        let (pos, total) = self.free.borrow_mut().insert(addr.0 as u32, total);
+        let mut file = self.file.borrow_mut();
+        write_vhdr(&mut file, pos, VDEL_MARK, total)?;
+        file.count(DAT_COUNT, -1)
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    addr: u32,
+    right: u32,
+    left: u32,
+    leaf: bool,
+    entries: Vec<(u32, Vec<u8>)>,
+    high_key: Vec<u8>
+}
+
+#[derive(Debug)]
+pub struct IdxFile {
+    file: RefCell<CtFile>,
+    keylen: usize,
+    node_size: usize,
+    maxkbl: usize,
+    maxkbn: usize,
+    /// Nodes that are not in the tree, to be used before the file grows
+    free: RefCell<Vec<u32>>
+}
+
+impl IdxFile {
+    pub fn open<S: Into<Vec<u8>>>(filename: S) -> Result<Self, Error> {
+        let file = CtFile::open(filename)?;
+        let idx = IdxFile {
+            keylen: file.get_u16(KEYLEN) as usize,
+            node_size: file.get_u16(NODE_SIZE) as usize,
+            maxkbl: file.get_u16(MAXKBL) as usize,
+            maxkbn: file.get_u16(MAXKBN) as usize,
+            file: RefCell::new(file),
+            free: RefCell::new(Vec::new())
+        };
+        *idx.free.borrow_mut() = idx.find_free_nodes()?;
+        Ok(idx)
+    }
+
+    /// Walks the tree to find the nodes that are not in it
+    fn find_free_nodes(&self) -> Result<Vec<u32>, Error> {
+        let mut used = BTreeSet::new();
+        let mut pending = vec![self.root()];
+        while let Some(addr) = pending.pop() {
+            if !used.insert(addr) {
+                return Err(Error::CTree(READ_ERR));
+            }
+            let node = self.read_node(addr)?;
+            if !node.leaf {
+                pending.extend(node.entries.iter().map(|entry| entry.0));
+            }
+        }
+        let file = self.file.borrow();
+        let end = u64::from(file.get_u32(NUMREC));
+        let node_size = self.node_size as u64;
+        Ok((1..).map(|n| n * node_size)
+            .take_while(|addr| addr + node_size - 1 <= end)
+            .map(|addr| addr as u32)
+            .filter(|addr| !used.contains(addr))
+            .collect())
+    }
+
+    fn check_key(&self, key: &[u8]) -> Result<(), Error> {
+        if key.len() == self.keylen {
+            Ok(())
+        } else {
+            Err(Error::BadKeyLength)
+        }
+    }
+
+    fn capacity(&self, leaf: bool) -> usize {
+        let bytes = if leaf { self.maxkbl } else { self.maxkbn };
+        bytes / (POINTER_LEN + self.keylen)
+    }
+
+    fn root(&self) -> u32 {
+        self.file.borrow().get_u32(ROOT)
+    }
+
+    fn read_node(&self, addr: u32) -> Result<Node, Error> {
+        let mut buf = vec![0u8; self.node_size];
+        self.file.borrow_mut().read_at(addr, &mut buf)?;
+        let nkv = LE::read_u16(&buf[8..10]) as usize;
+        let leaf = buf[17] != 0;
+        let entry_len = POINTER_LEN + self.keylen;
+        if NODE_HDR_LEN + nkv * entry_len > self.node_size {
+            return Err(Error::CTree(READ_ERR));
+        }
+        let entries = buf[NODE_HDR_LEN..NODE_HDR_LEN + nkv * entry_len].chunks(entry_len)
+            .map(|entry| (LE::read_u32(&entry[..POINTER_LEN]), entry[POINTER_LEN..].to_vec()))
+            .collect::<Vec<_>>();
+        let high_key = if leaf {
+            buf[NODE_HDR_LEN + self.maxkbl..NODE_HDR_LEN + self.maxkbl + self.keylen].to_vec()
+        } else {
+            entries.last().map(|entry| entry.1.clone()).unwrap_or_else(|| vec![0xFF; self.keylen])
+        };
+        Ok(Node {
+            addr,
+            right: LE::read_u32(&buf[0..4]),
+            left: LE::read_u32(&buf[4..8]),
+            leaf,
+            entries,
+            high_key
+        })
+    }
+
+    fn write_node(&self, node: &Node) -> Result<(), Error> {
+        let mut buf = vec![0u8; self.node_size];
+        let entry_len = POINTER_LEN + self.keylen;
+        LE::write_u32(&mut buf[0..4], node.right);
+        LE::write_u32(&mut buf[4..8], node.left);
+        LE::write_u16(&mut buf[8..10], node.entries.len() as u16);
+        LE::write_u16(&mut buf[10..12], (node.entries.len() * entry_len) as u16);
+        buf[17] = node.leaf as u8;
+        for (i, (pointer, key)) in node.entries.iter().enumerate() {
+            let start = NODE_HDR_LEN + i * entry_len;
+            LE::write_u32(&mut buf[start..start + POINTER_LEN], *pointer);
+            buf[start + POINTER_LEN..start + entry_len].copy_from_slice(key);
+        }
+        if node.leaf {
+            let start = NODE_HDR_LEN + self.maxkbl;
+            buf[start..start + self.keylen].copy_from_slice(&node.high_key);
+        }
+        self.file.borrow_mut().write_at(node.addr, &buf)
+    }
+
+    fn new_node(&self, leaf: bool) -> Result<Node, Error> {
+        let free = self.free.borrow_mut().pop();
+        let addr = match free {
+            Some(addr) => addr,
+            None => self.file.borrow_mut().allocate(self.node_size as u32)?
+        };
+        Ok(Node {
+            addr,
+            right: 0,
+            left: 0,
+            leaf,
+            entries: Vec::new(),
+            high_key: vec![0xFF; self.keylen]
+        })
+    }
+
+    fn set_root(&self, addr: u32) -> Result<(), Error> {
+        let mut file = self.file.borrow_mut();
+        file.set_u32(ROOT, addr);
+        file.set_u32(MEMBER_ROOT, addr);
+        file.write_header()
+    }
+
+    /// Walks from the root to the leaf that may hold `key`, returning the nonleaf nodes passed
+    /// through along with the index of the entry followed in each
+    fn find_leaf(&self, key: &[u8]) -> Result<(Vec<(Node, usize)>, Node), Error> {
+        let mut path = Vec::new();
+        let mut node = self.read_node(self.root())?;
+        while !node.leaf {
+            if node.entries.is_empty() {
+                return Err(Error::CTree(READ_ERR));
+            }
+            let index = node.entries.iter().position(|entry| &entry.1[..] >= key).unwrap_or(node.entries.len() - 1);
+            let child = node.entries[index].0;
+            path.push((node, index));
+            node = self.read_node(child)?;
+        }
+        Ok((path, node))
+    }
+
+    /// Moves the upper half of an overfull node into a new right sibling
+    fn split(&self, node: &mut Node) -> Result<Node, Error> {
+        let mut right = self.new_node(node.leaf)?;
+        let half = node.entries.len() / 2;
+        right.entries = node.entries.split_off(half);
+        right.high_key = node.high_key.clone();
+        right.right = node.right;
+        right.left = node.addr;
+        node.high_key = node.entries.last().expect("Split an empty node").1.clone();
+        node.right = right.addr;
+        if right.right != 0 {
+            let mut next = self.read_node(right.right)?;
+            next.left = right.addr;
+            self.write_node(&next)?;
+        }
+        Ok(right)
+    }
+
+    pub(super) fn add_key(&self, key: &[u8], dataddr: &DatAddr) -> Result<(), Error> {
+        self.check_key(key)?;
+        let (mut path, mut node) = self.find_leaf(key)?;
+        let index = match node.entries.binary_search_by(|entry| entry.1[..].cmp(key)) {
+            Ok(_) => return Err(Error::CTree(KDUP_ERR)),
+            Err(index) => index
+        };
+        self.file.borrow_mut().count(IDX_COUNT, 1)?;
+        node.entries.insert(index, (dataddr.0 as u32, key.to_vec()));
+        while node.entries.len() > self.capacity(node.leaf) {
+            let right = self.split(&mut node)?;
+            self.write_node(&node)?;
+            self.write_node(&right)?;
+            match path.pop() {
+                Some((mut parent, index)) => {
+                    parent.entries[index].1 = node.high_key.clone();
+                    parent.entries.insert(index + 1, (right.addr, right.high_key.clone()));
+                    node = parent;
+                },
+                None => {
+                    let mut root = self.new_node(false)?;
+                    root.entries.push((node.addr, node.high_key.clone()));
+                    root.entries.push((right.addr, right.high_key.clone()));
+                    self.write_node(&root)?;
+                    return self.set_root(root.addr);
+                }
+            }
+        }
+        self.write_node(&node)
+    }
+
+    pub(super) fn get_key(&self, key: &[u8]) -> Result<Option<DatAddr>, Error> {
+        self.check_key(key)?;
+        let (_, node) = self.find_leaf(key)?;
+        Ok(node.entries.iter().find(|entry| &entry.1[..] == key).map(|entry| DatAddr(entry.0 as i32)))
+    }
+
+    /// Finds the smallest key greater than `after`, starting at `node` and following right siblings
+    fn first_after(&self, mut node: Node, after: Option<&[u8]>) -> Result<Option<(Vec<u8>, DatAddr)>, Error> {
+        loop {
+            let found = node.entries.iter().find(|entry| after.is_none_or(|after| &entry.1[..] > after));
+            if let Some((pointer, key)) = found {
+                return Ok(Some((key.clone(), DatAddr(*pointer as i32))));
+            }
+            if node.right == 0 {
+                return Ok(None);
+            }
+            node = self.read_node(node.right)?;
+        }
+    }
+
+    pub(super) fn first_key(&self) -> Result<Option<(Vec<u8>, DatAddr)>, Error> {
+        let (_, node) = self.find_leaf(&vec![0u8; self.keylen])?;
+        self.first_after(node, None)
+    }
+
+    pub(super) fn next_key(&self, key: &[u8]) -> Result<Option<(Vec<u8>, DatAddr)>, Error> {
+        self.check_key(key)?;
+        let (_, node) = self.find_leaf(key)?;
+        self.first_after(node, Some(key))
+    }
+
+    /// Takes an emptied node out of its level and keeps it for reuse
+    fn unlink(&self, node: &Node) -> Result<(), Error> {
+        if node.left != 0 {
+            let mut left = self.read_node(node.left)?;
+            left.right = node.right;
+            self.write_node(&left)?;
+        }
+        if node.right != 0 {
+            let mut right = self.read_node(node.right)?;
+            right.left = node.left;
+            self.write_node(&right)?;
+        }
+        self.free.borrow_mut().push(node.addr);
+        Ok(())
+    }
+
+    pub(super) fn delete_key(&self, key: &[u8], addr: &DatAddr) -> Result<(), Error> {
+        self.check_key(key)?;
+        let (mut path, mut node) = self.find_leaf(key)?;
+        let index = node.entries.iter().position(|entry| &entry.1[..] == key).ok_or(Error::CTree(KDEL_ERR))?;
+        if node.entries[index].0 != addr.0 as u32 {
+            return Err(Error::CTree(KMAT_ERR));
+        }
+        node.entries.remove(index);
+        self.file.borrow_mut().count(IDX_COUNT, -1)?;
+        // The rightmost node of a level is never emptied out of the tree, so its parent never empties either
+        loop {
+            if node.entries.is_empty() && node.right != 0 {
+                self.unlink(&node)?;
+                let (mut parent, index) = path.pop().expect("Emptied the root");
+                parent.entries.remove(index);
+                node = parent;
+                continue;
+            }
+            if node.leaf && node.right != 0 {
+                node.high_key = node.entries.last().expect("Kept an empty leaf").1.clone();
+            }
+            self.write_node(&node)?;
+            let high_key = match node.entries.last() {
+                Some(entry) if !node.leaf => entry.1.clone(),
+                _ => node.high_key.clone()
+            };
+            match path.pop() {
+                Some((mut parent, index)) if parent.entries[index].1 != high_key => {
+                    parent.entries[index].1 = high_key;
+                    node = parent;
+                },
+                _ => return Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "ctreestd")))]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use byteorder::{ByteOrder, LE};
+
+    use super::*;
+    use ctree;
+
+    /// The templates as ctreestd.dll wrote them
+    const BLANK_IDX: &[u8] = include_bytes!("../../blank42.idx");
+    const BLANK_DAT: &[u8] = include_bytes!("../../blank42.dat");
+
+    /// Copies of the templates, removed again when dropped
+    struct Scratch {
+        idx: PathBuf,
+        dat: PathBuf
+    }
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let base = env::temp_dir().join(format!("propdump2cell42-{}-{}", name, std::process::id()));
+            let scratch = Scratch {
+                idx: base.with_extension("idx"),
+                dat: base.with_extension("dat")
+            };
+            fs::write(&scratch.idx, BLANK_IDX).unwrap();
+            fs::write(&scratch.dat, BLANK_DAT).unwrap();
+            scratch
+        }
+
+        fn open(&self) -> (IdxFile, DatFile) {
+            (IdxFile::open(self.idx.to_str().unwrap()).unwrap(), DatFile::open(self.dat.to_str().unwrap()).unwrap())
+        }
+
+        fn headers(&self) -> (Vec<u8>, Vec<u8>) {
+            (fs::read(&self.idx).unwrap()[..HEADER_LEN].to_vec(), fs::read(&self.dat).unwrap()[..HEADER_LEN].to_vec())
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.idx);
+            let _ = fs::remove_file(&self.dat);
+        }
+    }
+
+    fn key(n: u32) -> Vec<u8> {
+        let mut key = vec![0, 1, 0, 0, 0, 0];
+        LE::write_u32(&mut key[2..], n.swap_bytes());
+        key
+    }
+
+    fn data(n: u32) -> Vec<u8> {
+        (0..n % 700 + 1).map(|i| (n + i) as u8).collect()
+    }
+
+    /// Every key and its data, in order
+    fn contents(idx: &IdxFile, dat: &DatFile) -> Vec<(Vec<u8>, Vec<u8>)> {
+        ctree::keys(idx).map(|entry| {
+            let (key, addr) = entry.unwrap();
+            (key, dat.read_v_data(&addr).unwrap())
+        }).collect()
+    }
+
+    /// Checks the sibling links and high keys of every level
+    fn check_tree(idx: &IdxFile) {
+        let mut level = vec![idx.root()];
+        loop {
+            let nodes: Vec<Node> = level.iter().map(|addr| idx.read_node(*addr).unwrap()).collect();
+            for (i, node) in nodes.iter().enumerate() {
+                assert_eq!(node.left, if i == 0 { 0 } else { nodes[i - 1].addr });
+                assert_eq!(node.right, nodes.get(i + 1).map_or(0, |next| next.addr));
+                assert!(node.right == 0 || !node.entries.is_empty(), "Empty node left in the tree");
+                assert!(node.entries.iter().all(|entry| entry.1 <= node.high_key));
+                if node.right == 0 {
+                    assert_eq!(node.high_key, vec![0xFF; idx.keylen]);
+                }
+            }
+            if nodes[0].leaf {
+                return;
+            }
+            for node in &nodes {
+                for (pointer, key) in &node.entries {
+                    assert_eq!(&idx.read_node(*pointer).unwrap().high_key, key);
+                }
+            }
+            level = nodes.iter().flat_map(|node| node.entries.iter().map(|entry| entry.0)).collect();
+        }
+    }
+
+    #[test]
+    fn rewriting_the_blanks_leaves_them_unchanged() {
+        let scratch = Scratch::new("rewrite");
+        {
+            let (idx, dat) = scratch.open();
+            let root = idx.read_node(idx.root()).unwrap();
+            idx.write_node(&root).unwrap();
+            idx.file.borrow_mut().write_header().unwrap();
+            let version = contents(&idx, &dat);
+            assert_eq!(version.len(), 1);
+            let (_, addr) = idx.first_key().unwrap().unwrap();
+            dat.write_v_data(&addr, &version[0].1).unwrap();
+            dat.file.borrow_mut().write_header().unwrap();
+        }
+        assert_eq!(fs::read(&scratch.idx).unwrap(), BLANK_IDX);
+        assert_eq!(fs::read(&scratch.dat).unwrap(), BLANK_DAT);
+    }
+
+    #[test]
+    fn counts_match_the_blanks() {
+        let scratch = Scratch::new("blank-counts");
+        let (idx, dat) = scratch.open();
+        let keys = contents(&idx, &dat).len();
+        assert_eq!(idx.file.borrow().get_u32(IDX_COUNT) as usize, keys);
+        assert_eq!(dat.file.borrow().get_u32(DAT_COUNT), 1);
+    }
+
+    #[test]
+    fn inserts_deletes_and_appends_survive_a_reopen() {
+        let scratch = Scratch::new("round-trip");
+        let mut expected = BTreeMap::new();
+        {
+            let (idx, dat) = scratch.open();
+            expected.extend(contents(&idx, &dat));
+            for n in 0..3000 {
+                ctree::insert_or_append(&idx, &dat, &key(n), &data(n)).unwrap();
+                expected.insert(key(n), data(n));
+            }
+            for n in (0..3000).filter(|n| n % 3 == 0 || (1000..2000).contains(n)) {
+                assert_eq!(ctree::take(&idx, &dat, &key(n)).unwrap(), expected.remove(&key(n)));
+            }
+            for n in (0..3000).step_by(7) {
+                ctree::insert_or_append(&idx, &dat, &key(n), &data(n + 1)).unwrap();
+                expected.entry(key(n)).or_insert_with(Vec::new).extend(data(n + 1));
+            }
+        }
+        let (idx, dat) = scratch.open();
+        check_tree(&idx);
+        assert_eq!(contents(&idx, &dat), expected.into_iter().collect::<Vec<_>>());
+        let (idx_header, dat_header) = scratch.headers();
+        let records = contents(&idx, &dat).len() as u32;
+        assert_eq!(LE::read_u32(&idx_header[IDX_COUNT..]), records);
+        assert_eq!(LE::read_u32(&dat_header[DAT_COUNT..]), records);
+    }
+
+    #[test]
+    fn released_space_is_reused_after_a_reopen() {
+        let scratch = Scratch::new("reuse");
+        {
+            let (idx, dat) = scratch.open();
+            for n in 0..200 {
+                ctree::insert_or_append(&idx, &dat, &key(n), &data(n)).unwrap();
+            }
+        }
+        let size = (fs::metadata(&scratch.idx).unwrap().len(), fs::metadata(&scratch.dat).unwrap().len());
+        for _ in 0..3 {
+            {
+                let (idx, dat) = scratch.open();
+                for n in 0..200 {
+                    ctree::take(&idx, &dat, &key(n)).unwrap().unwrap();
+                }
+            }
+            let (idx, dat) = scratch.open();
+            for n in 0..200 {
+                ctree::insert_or_append(&idx, &dat, &key(n), &data(n)).unwrap();
+            }
+            check_tree(&idx);
+        }
+        assert_eq!((fs::metadata(&scratch.idx).unwrap().len(), fs::metadata(&scratch.dat).unwrap().len()), size);
+    }
+
+    #[test]
+    fn emptied_nodes_leave_the_tree() {
+        let scratch = Scratch::new("empty-nodes");
+        let (idx, dat) = scratch.open();
+        for n in 0..5000 {
+            ctree::insert_or_append(&idx, &dat, &key(n), &[]).unwrap();
+        }
+        for n in (0..5000).filter(|n| n % 1000 != 999) {
+            ctree::take(&idx, &dat, &key(n)).unwrap().unwrap();
+            if n % 500 == 0 {
+                check_tree(&idx);
+            }
+        }
+        check_tree(&idx);
+        let keys: Vec<Vec<u8>> = ctree::keys(&idx).map(|entry| entry.unwrap().0).collect();
+        let mut expected: Vec<Vec<u8>> = (0..5).map(|n| key(n * 1000 + 999)).collect();
+        expected.push(b"Versio".to_vec());
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn full_file_is_left_alone() {
+        let scratch = Scratch::new("full");
+        let (_, dat) = scratch.open();
+        dat.file.borrow_mut().set_u32(NUMREC, DAT_LIMIT as u32);
+        match dat.new_v_data(100) {
+            Err(Error::OutOfSpace) => (),
+            result => panic!("Expected OutOfSpace, got {:?}", result)
+        }
+        assert_eq!(fs::metadata(&scratch.dat).unwrap().len(), BLANK_DAT.len() as u64);
+    }
+}
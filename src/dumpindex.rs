@@ -0,0 +1,257 @@
+//! Sidecar indexes for uncompressed propdumps: where every cell's records are, so that the records of a few cells
+//! can be read without going through the whole propdump. The index of `FILE` is kept in `FILE.cells`.
+//!
+//! All numbers are little endian. The file starts with a header:
+//!
+//! * 8 bytes: `P2C42IDX`
+//! * u16: format version, currently 1
+//! * u8: version of the propdump
+//! * u8: reserved
+//! * u32: number of cells
+//! * u64: offset of the cell table
+//! * u64: length of the propdump when it was indexed
+//! * u64: modification time of the propdump when it was indexed, in seconds since 1970
+//!
+//! Then comes a block for every cell: the record number and byte offset of each of its records, as u64s, in
+//! propdump order. The cell table at the end is laid out as in pack files, with the record count as the object
+//! count, so it doubles as a quick count of the objects in every cell.
+
+use failure;
+use byteorder::{ByteOrder, LE, ReadBytesExt, WriteBytesExt};
+use encoding::types::EncodingRef;
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::sync::atomic::Ordering;
+use std::time::UNIX_EPOCH;
+
+use aw::Object;
+use cellsort::CellSorter;
+use input;
+use pack::{self, CellEntry, CellFileHeader};
+use propdump::Propdump;
+use RUNNING;
+
+const MAGIC: &[u8; 8] = b"P2C42IDX";
+const FORMAT_VERSION: u16 = 1;
+const RECORD_LEN: usize = 16;
+
+/// Where the index of a propdump is kept
+pub fn path(dump: &str) -> String {
+    format!("{}.cells", dump)
+}
+
+/// Length and modification time of a propdump, to tell whether its index is out of date
+fn stamp(dump: &str) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(dump)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+    Ok((metadata.len(), modified))
+}
+
+/// Reads through a propdump once and writes its index
+pub fn build(dump: &str, recover: bool, memory_budget: usize) -> Result<(), failure::Error> {
+    ensure!(!input::is_compressed(dump).map_err(|err| format_err!("Unable to open {}: {}", dump, err))?,
+            "{} is compressed. Only uncompressed propdumps can be indexed, as they are read from by seeking", dump);
+    let (dump_len, modified) = stamp(dump)?;
+    let mut propdump = Propdump::new(BufReader::new(File::open(dump)?)).map_err(|err| format_err!("{}: {}", dump, err))?;
+    propdump.set_recover(recover);
+    let mut sorter = CellSorter::new(".", memory_budget);
+    let mut entry = [0u8; RECORD_LEN];
+    let mut records = 0;
+    while let Some(record) = propdump.next_raw() {
+        if !RUNNING.load(Ordering::SeqCst) {
+            eprintln!("Quitting due to Ctrl-C, {} not written", path(dump));
+            return Ok(());
+        }
+        let record = record.map_err(|err| format_err!("{}: {}", dump, err))?;
+        let (number, offset) = propdump.position();
+        LE::write_u64(&mut entry[0..8], number);
+        LE::write_u64(&mut entry[8..16], offset);
+        sorter.push(record.cell(), &entry)?;
+        records += 1;
+    }
+    if recover {
+        eprintln!("{} damaged records skipped", propdump.lost_records());
+    }
+
+    let mut rest = Vec::with_capacity(16);
+    rest.write_u64::<LE>(dump_len)?;
+    rest.write_u64::<LE>(modified)?;
+    let header = CellFileHeader {
+        magic: MAGIC,
+        format: FORMAT_VERSION,
+        version: propdump.version(),
+        flags: 0,
+        rest
+    };
+    let cells = pack::write_cell_file(&path(dump), header, sorter, |data, block| {
+        block.extend_from_slice(data);
+        Ok((data.len() / RECORD_LEN) as u32)
+    })?;
+    println!("Indexed {} records in {} cells to {}", records, cells, path(dump));
+    Ok(())
+}
+
+/// A propdump opened along with its index, with the cell table in memory
+pub struct IndexedPropdump {
+    name: String,
+    propdump: Propdump<BufReader<File>>,
+    index: File,
+    cells: Vec<CellEntry>
+}
+
+impl IndexedPropdump {
+    /// Gives `None` if the propdump has no index, or the index is out of date
+    pub fn open(dump: &str) -> Result<Option<Self>, failure::Error> {
+        let index_path = path(dump);
+        let mut index = match File::open(&index_path) {
+            Ok(index) => index,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => bail!("Unable to open {}: {}", index_path, err)
+        };
+        let mut magic = [0u8; 8];
+        index.read_exact(&mut magic)?;
+        ensure!(&magic == MAGIC, "{} is not a propdump index", index_path);
+        let format = index.read_u16::<LE>()?;
+        ensure!(format == FORMAT_VERSION, "{} is a version {} propdump index, only version {} is supported", index_path, format, FORMAT_VERSION);
+        index.read_u8()?;
+        index.read_u8()?;
+        let count = index.read_u32::<LE>()?;
+        let table_offset = index.read_u64::<LE>()?;
+        let dump_len = index.read_u64::<LE>()?;
+        let modified = index.read_u64::<LE>()?;
+        if stamp(dump)? != (dump_len, modified) {
+            eprintln!("Ignoring {}, as {} has changed since it was indexed", index_path, dump);
+            return Ok(None);
+        }
+        index.seek(SeekFrom::Start(table_offset))?;
+        let cells = CellEntry::read_table(BufReader::new(&mut index), count)?;
+        let propdump = Propdump::new(BufReader::new(File::open(dump)?)).map_err(|err| format_err!("{}: {}", dump, err))?;
+        Ok(Some(IndexedPropdump {
+            name: dump.to_string(),
+            propdump,
+            index,
+            cells
+        }))
+    }
+
+    pub fn version(&self) -> u8 {
+        self.propdump.version()
+    }
+
+    pub fn propdump_mut(&mut self) -> &mut Propdump<BufReader<File>> {
+        &mut self.propdump
+    }
+
+    pub fn encoding(&self) -> EncodingRef {
+        self.propdump.encoding()
+    }
+
+    /// Every cell, sorted by cell x then cell z
+    pub fn cells(&self) -> &[CellEntry] {
+        &self.cells
+    }
+
+    /// Reads the records of a cell from the propdump
+    pub fn read_cell(&mut self, cell: &CellEntry) -> Result<Vec<Object>, failure::Error> {
+        let mut block = vec![0; cell.len as usize];
+        self.index.seek(SeekFrom::Start(cell.offset))?;
+        self.index.read_exact(&mut block)?;
+        let mut objects = Vec::with_capacity(block.len() / RECORD_LEN);
+        for entry in block.chunks(RECORD_LEN) {
+            let (record, offset) = (LE::read_u64(&entry[0..8]), LE::read_u64(&entry[8..16]));
+            self.propdump.seek(record, offset)?;
+            match self.propdump.next() {
+                Some(Ok(object)) => objects.push(object),
+                Some(Err(err)) => bail!("{}: {}", self.name, err),
+                None => bail!("{}: the index points past the end of the propdump", self.name)
+            }
+        }
+        Ok(objects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::process;
+
+    use pack::cells_in;
+    use propdump::PropdumpWriter;
+
+    use super::*;
+
+    /// A propdump in the temporary directory, removed again along with its index when dropped
+    struct Scratch(String);
+
+    impl Scratch {
+        fn new(name: &str, objects: &[Object]) -> Self {
+            let scratch = Scratch(env::temp_dir().join(format!("propdump2cell42-{}-{}.txt", name, process::id())).to_str().unwrap().to_string());
+            let mut writer = PropdumpWriter::new(File::create(&scratch.0).unwrap(), 4).unwrap();
+            for object in objects {
+                writer.write(object).unwrap();
+            }
+            writer.flush().unwrap();
+            scratch
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+            let _ = fs::remove_file(path(&self.0));
+        }
+    }
+
+    /// Objects scattered over cells on both sides of 0, in no particular order
+    fn objects() -> Vec<Object> {
+        (0..300).map(|n| Object {
+            citnum: n,
+            x: (n * 37 % 41 - 20) * 1000 + n,
+            z: (n * 53 % 29 - 14) * 1000 - n,
+            type_: n % 4,
+            name: format!("model{}.rwx", n),
+            data: vec![n as u8; (n % 3) as usize],
+            ..Object::default()
+        }).collect()
+    }
+
+    fn by_citnum(mut objects: Vec<Object>) -> String {
+        objects.sort_by_key(|object| object.citnum);
+        format!("{:?}", objects)
+    }
+
+    #[test]
+    fn exactly_the_records_in_range_are_read_through_the_index() {
+        let objects = objects();
+        let scratch = Scratch::new("index", &objects);
+        build(&scratch.0, false, 1 << 20).unwrap();
+        let mut dump = IndexedPropdump::open(&scratch.0).unwrap().unwrap();
+        assert_eq!(dump.version(), 4);
+        let cells = dump.cells().to_vec();
+        assert_eq!(cells.iter().map(|cell| cell.objects).sum::<u32>(), 300);
+
+        let (x, z) = (-3..=5, -7..=2);
+        let mut read = Vec::new();
+        for cell in cells_in(&cells, x.clone(), z.clone()) {
+            read.extend(dump.read_cell(&cell).unwrap());
+        }
+        let expected: Vec<Object> = objects.into_iter().filter(|object| {
+            let loc = object.location();
+            x.contains(&loc.cell_x) && z.contains(&loc.cell_z)
+        }).collect();
+        assert!(!expected.is_empty());
+        assert_eq!(by_citnum(read), by_citnum(expected));
+    }
+
+    #[test]
+    fn indexes_of_changed_propdumps_are_ignored() {
+        let scratch = Scratch::new("stale-index", &objects()[..10]);
+        build(&scratch.0, false, 1 << 20).unwrap();
+        assert!(IndexedPropdump::open(&scratch.0).unwrap().is_some());
+        OpenOptions::new().append(true).open(&scratch.0).unwrap().write_all(b"1 2 0 0 0 0 0 0 0 3 0 0 0 a.r\r\n").unwrap();
+        assert!(IndexedPropdump::open(&scratch.0).unwrap().is_none());
+    }
+}
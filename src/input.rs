@@ -0,0 +1,149 @@
+use failure;
+use flate2::bufread::MultiGzDecoder;
+use bzip2::bufread::MultiBzDecoder;
+use lzma_rust2::XzReader;
+use ruzstd::decoding::{FrameDecoder, StreamingDecoder};
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+
+const GZIP_MAGIC: &[u8] = b"\x1F\x8B";
+const BZIP2_MAGIC: &[u8] = b"BZh";
+const XZ_MAGIC: &[u8] = b"\xFD7zXZ\x00";
+const ZSTD_MAGIC: &[u8] = b"\x28\xB5\x2F\xFD";
+/// Bytes needed to tell every format apart
+const MAGIC_LEN: usize = 6;
+
+/// Opens a propdump, decompressing it on the fly if it is compressed
+pub fn open(path: &str) -> Result<Box<dyn BufRead + Send>, failure::Error> {
+    let file = File::open(path).map_err(|err| format_err!("Unable to open {}: {}", path, err))?;
+    Ok(decompress(BufReader::new(file))?)
+}
+
+/// Whether a file starts like one of the compressed streams `decompress` recognizes
+pub fn is_compressed(path: &str) -> io::Result<bool> {
+    let start = read_start(&mut File::open(path)?)?;
+    Ok([GZIP_MAGIC, BZIP2_MAGIC, XZ_MAGIC, ZSTD_MAGIC].iter().any(|magic| start.starts_with(magic)))
+}
+
+/// Reads `MAGIC_LEN` bytes, however many reads that takes, or what there is before the end
+fn read_start<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let mut start = Vec::with_capacity(MAGIC_LEN);
+    r.take(MAGIC_LEN as u64).read_to_end(&mut start)?;
+    Ok(start)
+}
+
+/// Recognizes gzip, bzip2, xz and zstd streams by their magic bytes. Anything else is read as is.
+pub fn decompress<R: BufRead + Send + 'static>(mut r: R) -> io::Result<Box<dyn BufRead + Send>> {
+    let start = read_start(&mut r)?;
+    let r = io::Cursor::new(start.clone()).chain(r);
+    Ok(if start.starts_with(GZIP_MAGIC) {
+        Box::new(BufReader::new(MultiGzDecoder::new(r)))
+    } else if start.starts_with(BZIP2_MAGIC) {
+        Box::new(BufReader::new(MultiBzDecoder::new(r)))
+    } else if start.starts_with(XZ_MAGIC) {
+        Box::new(BufReader::new(XzReader::new(r, true)))
+    } else if start.starts_with(ZSTD_MAGIC) {
+        Box::new(BufReader::new(ZstdReader::new(r)?))
+    } else {
+        Box::new(r)
+    })
+}
+
+/// Decodes zstd frames one after another, as written by multithreaded compressors
+struct ZstdReader<R: BufRead> {
+    frame: Option<StreamingDecoder<R, FrameDecoder>>
+}
+
+impl<R: BufRead> ZstdReader<R> {
+    fn new(r: R) -> io::Result<Self> {
+        let frame = StreamingDecoder::new(r).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(ZstdReader {
+            frame: Some(frame)
+        })
+    }
+}
+
+impl<R: BufRead> Read for ZstdReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = match self.frame {
+                Some(ref mut frame) => frame.read(buf)?,
+                None => return Ok(0)
+            };
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            let mut source = self.frame.take().unwrap().into_inner();
+            if source.fill_buf()?.is_empty() {
+                return Ok(0);
+            }
+            *self = ZstdReader::new(source)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = b"propdump version 4\r\n";
+    /// `TEXT` as gzip, bzip2, xz and zstd wrote it
+    const GZIP: &[u8] = b"\x1F\x8B\x08\x00\x00\x00\x00\x00\x02\x03\x2B\x28\xCA\x2F\x48\x29\xCD\x2D\x50\x28\x4B\x2D\x2A\xCE\xCC\xCF\x53\x30\xE1\xE5\x02\x00\x00\x1A\x52\x32\x14\x00\x00\x00";
+    const BZIP2: &[u8] = b"\x42\x5A\x68\x39\x31\x41\x59\x26\x53\x59\x45\x7C\x9A\x6E\x00\x00\x07\x59\x80\x00\x12\x40\x00\x04\x00\x06\x23\xDB\x00\x20\x00\x22\x9A\x69\xA3\x4C\x3D\x50\xA6\x00\x01\x15\xE1\x90\xB2\xE8\x16\x9C\x62\x7E\x2E\xE4\x8A\x70\xA1\x20\x8A\xF9\x34\xDC";
+    const XZ: &[u8] = b"\xFD\x37\x7A\x58\x5A\x00\x00\x01\x69\x22\xDE\x36\x04\xC0\x18\x14\x21\x01\x16\x00\x00\x00\x00\x00\x00\x00\x00\x00\xFA\xDB\x09\xF5\x01\x00\x13propdump version 4\r\n\x00\x00\x1A\x52\x32\x00\x01\x30\x14\xA5\x57\x1A\xE5\x90\x42\x99\x0D\x01\x00\x00\x00\x00\x01\x59\x5A";
+    const ZSTD: &[u8] = b"\x28\xB5\x2F\xFD\x20\x14\xA1\x00\x00propdump version 4\r\n";
+
+    /// Hands out one byte per read, like a pipe that is being written slowly
+    struct Trickle {
+        bytes: Vec<u8>,
+        pos: usize
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = self.fill_buf()?.len().min(buf.len());
+            buf[..read].copy_from_slice(&self.bytes[self.pos..self.pos + read]);
+            self.consume(read);
+            Ok(read)
+        }
+    }
+
+    impl BufRead for Trickle {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            let end = (self.pos + 1).min(self.bytes.len());
+            Ok(&self.bytes[self.pos..end])
+        }
+
+        fn consume(&mut self, amt: usize) {
+            self.pos += amt;
+        }
+    }
+
+    fn read_trickled(bytes: &[u8]) -> Vec<u8> {
+        let mut read = Vec::new();
+        decompress(Trickle { bytes: bytes.to_vec(), pos: 0 }).unwrap().read_to_end(&mut read).unwrap();
+        read
+    }
+
+    #[test]
+    fn every_format_is_recognized_from_short_reads() {
+        for compressed in &[GZIP, BZIP2, XZ, ZSTD] {
+            assert_eq!(read_trickled(compressed), TEXT);
+        }
+    }
+
+    #[test]
+    fn concatenated_streams_are_read_one_after_another() {
+        for compressed in &[GZIP, BZIP2, XZ, ZSTD] {
+            assert_eq!(read_trickled(&[*compressed, *compressed].concat()), [TEXT, TEXT].concat());
+        }
+    }
+
+    #[test]
+    fn anything_else_is_read_as_is() {
+        for plain in &[TEXT, b"\xFD7z", b""] {
+            assert_eq!(read_trickled(plain), *plain);
+        }
+    }
+}
@@ -1,62 +1,103 @@
 #[macro_use]
 extern crate lazy_static;
+#[cfg(feature = "ctreestd")]
 extern crate libloading as lib;
 extern crate byteorder;
 extern crate ctrlc;
 extern crate clap;
 extern crate encoding;
 extern crate regex;
+extern crate flate2;
+extern crate bzip2;
+extern crate lzma_rust2;
+extern crate ruzstd;
 #[macro_use] extern crate failure;
 
-use byteorder::{ByteOrder, LE};
+use std::ascii;
+use std::borrow::Cow;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::str::FromStr;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::thread;
 use clap::{App, Arg};
 
 mod ctree;
+mod cache;
 mod aw;
 mod propdump;
 mod teleports;
+mod shard;
+mod cellsort;
+mod input;
+mod pipeline;
+mod pack;
+mod dumpindex;
+mod stats;
+mod budget;
+mod codepage;
+mod objdata;
+mod compat;
 
-use teleports::{Teleports, TeleportAppender};
+use teleports::{Teleport, Teleports, TeleportAppender};
+use cache::{Cache, CellSink, DryRun, UpdatedCache, UpdateMode};
+use shard::ShardedCache;
+use cellsort::CellSorter;
+use propdump::{Propdump, Schema};
+use pipeline::Input;
+use pack::{CellEntry, Pack, PackWriter};
+use dumpindex::IndexedPropdump;
+use stats::Stats;
+use budget::{CellSizes, Growth};
+use aw::{EncodedText, FieldLimits, LongFields};
+use codepage::CodePage;
+use objdata::ObjectKind;
+use compat::Downgrade;
+use encoding::types::EncodingRef;
 
 static RUNNING: AtomicBool = AtomicBool::new(true);
 
-#[derive(Debug)]
-/// Cannot (yet) rewrite already written cells
-struct ObjectWriter<'idx, 'dat> {
-    cell: Option<(i16, i16)>,
-    idx: &'idx ctree::IdxFile,
-    dat: &'dat ctree::DatFile,
-    cell_data_buffer: Vec<u8>
+/// Gathers the objects of each cell and hands every cell to the sink exactly once, whatever order the propdump is in
+struct ObjectWriter<S: CellSink> {
+    sink: S,
+    sorter: Option<CellSorter>,
+    downgrade: Option<Downgrade>,
+    limits: FieldLimits,
+    object_buffer: Vec<u8>
 }
 
-impl<'idx, 'dat> ObjectWriter<'idx, 'dat> {
-    pub fn new(idx: &'idx ctree::IdxFile, dat: &'dat ctree::DatFile) -> Self {
+impl<S: CellSink> ObjectWriter<S> {
+    pub fn new(sink: S, memory_budget: usize, downgrade: Option<Downgrade>, long_fields: LongFields, code_page: CodePage) -> Self {
         ObjectWriter {
-            cell: None,
-            idx: idx,
-            dat: dat,
-            cell_data_buffer: vec![]
+            sink,
+            sorter: Some(CellSorter::new(".", memory_budget)),
+            downgrade,
+            limits: FieldLimits::new(long_fields, code_page),
+            object_buffer: vec![]
         }
     }
 
     pub fn add_object(&mut self, object: &aw::Object) -> Result<(), failure::Error> {
+        let (object, text) = match fit(object, self.downgrade.as_mut(), &mut self.limits)? {
+            Some(fitted) => fitted,
+            None => return Ok(())
+        };
         let loc = object.location();
-        if self.cell.is_some() && self.cell != Some((loc.cell_x, loc.cell_z)) {
-            self.write_current_cell()?;
-        }
-        self.cell = Some((loc.cell_x, loc.cell_z));
-        object.write(&mut self.cell_data_buffer)?;
-        Ok(())
+        self.object_buffer.clear();
+        object.write_encoded(&mut self.object_buffer, &text)?;
+        self.sorter.as_mut().unwrap().push((loc.cell_x, loc.cell_z), &self.object_buffer)
     }
-    
-    pub fn write_current_cell(&mut self) -> Result<(), failure::Error> {
-        if self.cell.is_none() {
-            return Ok(());
+
+    /// Writes every gathered cell
+    pub fn finish(&mut self) -> Result<(), failure::Error> {
+        let sorter = match self.sorter.take() {
+            Some(sorter) => sorter,
+            None => return Ok(())
+        };
+        if let Some(ref downgrade) = self.downgrade {
+            downgrade.report();
         }
-        let (cell_x, cell_z) = self.cell.unwrap();
-        self.cell = None;
+        self.limits.report();
+        let sink = &mut self.sink;
         // Currently hard to avoid accidental appending to cell sequence, and it seems to be unneeded for AW
         // let mut sequence_key = [0u8; 6];
         // let mut sequence_value = [0u8; 4];
@@ -65,27 +106,66 @@ impl<'idx, 'dat> ObjectWriter<'idx, 'dat> {
         // LE::write_i16(&mut sequence_key[4..6], cell_z);
         // LE::write_i32(&mut sequence_value, 1);
         // ctree::insert(&self.idx, &self.dat, &sequence_key, &sequence_value)?;
-        let mut celldata_key = [0u8; 6];
-        LE::write_u16(&mut celldata_key[0..2], 1);
-        LE::write_i16(&mut celldata_key[2..4], cell_x);
-        LE::write_i16(&mut celldata_key[4..6], cell_z);
-        ctree::insert_or_append(&self.idx, &self.dat, &celldata_key, &self.cell_data_buffer)?;
-        self.cell_data_buffer.clear();
-        Ok(())
+        sorter.finish(|cell_x, cell_z, data| sink.write_cell(cell_x, cell_z, data))?;
+        sink.finish()
     }
 }
 
-impl<'idx, 'dat> Drop for ObjectWriter<'idx, 'dat> {
+impl<S: CellSink> Drop for ObjectWriter<S> {
     fn drop(&mut self) {
-        let result = self.write_current_cell();
+        let result = self.finish();
         if result.is_err() {
-            println!("Unable to write final cell!");
+            eprintln!("Unable to write final cells!");
         }
     }
 }
 
+/// The object as it goes into the cache: downgraded, if asked for, then with its fields fitted to the cache.
+/// Comes with its text encoded for the cache, or `None` if it is to be left out.
+fn fit<'a>(object: &'a aw::Object, downgrade: Option<&mut Downgrade>, limits: &mut FieldLimits) -> Result<Option<(Cow<'a, aw::Object>, EncodedText)>, failure::Error> {
+    let object = match downgrade {
+        Some(downgrade) => match downgrade.apply(object) {
+            Some(object) => object,
+            None => return Ok(None)
+        },
+        None => Cow::Borrowed(object)
+    };
+    let (fitted, text) = match limits.apply(&object)? {
+        Some((Cow::Owned(fitted), text)) => (Some(fitted), text),
+        Some((Cow::Borrowed(_), text)) => (None, text),
+        None => return Ok(None)
+    };
+    Ok(Some((fitted.map(Cow::Owned).unwrap_or(object), text)))
+}
+
+/// How to grow the areas around teleports to fit a budget
+struct Budget {
+    teleports: Vec<Teleport>,
+    bytes: u64,
+    max_radius: Option<i16>,
+    growth: Growth
+}
+
 struct Config {
+    inputs: Vec<String>,
+    inspect: bool,
+    filter: bool,
+    pack: Option<String>,
+    index: bool,
+    stats: bool,
+    dry_run: bool,
+    recover: bool,
+    to_propdump: Option<u8>,
+    shard_region: Option<i16>,
+    update: Option<UpdateMode>,
+    memory_budget: usize,
+    downgrade: Option<Downgrade>,
+    long_fields: LongFields,
+    code_page: CodePage,
+    input_encoding: Option<EncodingRef>,
+    threads: usize,
     teleports: Option<Teleports>,
+    budget: Option<Budget>,
     citnums: Option<Vec<i32>>,
     teleport_appender: Option<TeleportAppender>
 }
@@ -94,20 +174,39 @@ fn config() -> Result<Config, failure::Error> {
     let matches = App::new("Propdump to Cell 4.2")
         .author("Sgeo <sgeoster@gmail.com>")
         .about("Converts a propdump to Active Worlds 4.2 standalone cache files")
+        .arg(Arg::with_name("input")
+             .long("input")
+             .short("I")
+             .takes_value(true)
+             .multiple(true)
+             .value_name("FILE")
+             .help("Reads the propdump from one or more files, one after another, instead of standard input. Files compressed with gzip, bzip2, xz or zstd are decompressed on the fly, as is standard input"))
         .arg(Arg::with_name("teleports")
              .long("teleports")
              .short("t")
              .takes_value(true)
              .value_name("TELEPORTS")
-             .requires("radius")
-             .help("Specifies a teleport.txt file. Only data in an area near a point in the teleport.txt file will be included"))
+             .help("Specifies a teleport.txt file. Only data in an area near a point in the teleport.txt file will be included. Requires a radius or a budget"))
         .arg(Arg::with_name("radius")
              .long("radius")
              .short("r")
              .takes_value(true)
              .value_name("RADIUS")
              .requires("teleports")
-             .help("Specify how many coordinates north, west, east, and south of each location in the teleports to include"))
+             .help("Specify how many coordinates north, west, east, and south of each location in the teleports to include. With a budget, the furthest any area may grow"))
+        .arg(Arg::with_name("budget")
+             .long("budget")
+             .takes_value(true)
+             .value_name("MEGABYTES")
+             .requires("teleports")
+             .help("Instead of a fixed radius, grows the area around each teleport one ring of cells at a time for as long as cell.dat stays within MEGABYTES, then reports the radius each teleport got. Reads the --input files twice, once to measure the cells"))
+        .arg(Arg::with_name("grow")
+             .long("grow")
+             .takes_value(true)
+             .value_name("ORDER")
+             .possible_values(&["priority", "even"])
+             .requires("budget")
+             .help("With a budget, whether each teleport grows as far as it can before the next one in the file starts, or all of them grow a ring at a time in turn. Defaults to priority"))
         .arg(Arg::with_name("citnum")
              .long("citnum")
              .short("c")
@@ -128,15 +227,173 @@ fn config() -> Result<Config, failure::Error> {
              .value_name("WORLD")
              .requires("append")
              .help("Specifies a world name when searching for teleports in the propdump. Other worlds will not be included. Does NOT affect the teleports option, which will use all listed teleports regardless of world"))
+         .arg(Arg::with_name("shard")
+             .long("shard")
+             .short("s")
+             .takes_value(true)
+             .value_name("REGION-SIZE")
+             .help("Instead of stopping at the 2GB AW limit, continues in cell.001.idx/dat, cell.002.idx/dat and so on. Cells are kept together in square regions REGION-SIZE cells wide, listed in cell.shards.txt"))
+         .arg(Arg::with_name("update")
+             .long("update")
+             .short("u")
+             .takes_value(true)
+             .value_name("MODE")
+             .possible_values(&["append", "replace", "dedupe"])
+             .conflicts_with("shard")
+             .help("Adds to the existing cell.idx and cell.dat instead of starting from blank ones. Cells that already have objects get the new objects appended, are replaced by the new objects, or get only the new objects that are not already there (same citizen, time, position and model)"))
+         .arg(Arg::with_name("memory")
+             .long("memory")
+             .short("m")
+             .takes_value(true)
+             .value_name("MEGABYTES")
+             .default_value("512")
+             .help("How much object data to gather in memory before spilling sorted runs to cell.sort.N.tmp files in the current directory. Objects are grouped by cell either way, so the propdump can be in any order"))
+         .arg(Arg::with_name("long-fields")
+             .long("long-fields")
+             .takes_value(true)
+             .value_name("POLICY")
+             .possible_values(&["truncate", "drop", "fail"])
+             .default_value("truncate")
+             .help("What to do with objects whose name, description or action is over 255 bytes, or whose data is over 65535 bytes, which the cache cannot hold: shorten the text (objects with too much data are dropped), leave the object out, or stop. Every object affected is reported"))
+         .arg(Arg::with_name("downgrade")
+             .long("downgrade")
+             .conflicts_with_all(&["inspect", "to-propdump", "filter", "pack", "index"])
+             .help("Makes objects fit for the AW 4.2 browser: zones, particle emitters, movers and cameras are left out, objects of other types it does not know become plain objects, and actions lose the triggers and commands it does not understand. What was changed is summarized at the end"))
+         .arg(Arg::with_name("marker")
+             .long("marker")
+             .takes_value(true)
+             .value_name("MODEL")
+             .requires("downgrade")
+             .help("With --downgrade, puts this model in place of zones, particle emitters, movers and cameras instead of leaving them out, so that where they were can be seen"))
+         .arg(Arg::with_name("code-page")
+             .long("code-page")
+             .takes_value(true)
+             .value_name("CODE-PAGE")
+             .default_value("windows-1252")
+             .help("The code page the cache's text is in, which should be the one of the Windows system running AW, such as windows-1251 for Cyrillic. Also used to read the cache with --inspect and --to-propdump. Characters the code page lacks are written as '?' and reported"))
+         .arg(Arg::with_name("transliterate")
+             .long("transliterate")
+             .help("Writes characters the code page lacks as the closest ones it has where there are any, such as curly quotes as straight ones and accented letters without their accents, instead of as '?'"))
+         .arg(Arg::with_name("input-encoding")
+             .long("input-encoding")
+             .takes_value(true)
+             .value_name("CODE-PAGE")
+             .help("The code page the text of version 3 and 4 propdumps is in, such as windows-1251 or shift_jis. By default it is guessed from the first records, falling back to windows-1252. Version 5 propdumps are always UTF-8"))
+         .arg(Arg::with_name("filter")
+             .long("filter")
+             .short("f")
+             .conflicts_with_all(&["shard", "update", "inspect", "to-propdump"])
+             .help("Writes the selected objects to standard output as a propdump of the same version and encoding, instead of creating cache files. Every input must be of the same version and encoding"))
+         .arg(Arg::with_name("pack")
+             .long("pack")
+             .takes_value(true)
+             .value_name("PACK")
+             .conflicts_with_all(&["shard", "update", "inspect", "to-propdump", "filter"])
+             .help("Writes the selected objects to a pack file instead of creating cache files. Pack files can be given to --input instead of propdumps, and are much quicker to read, especially when selecting with --teleports"))
+         .arg(Arg::with_name("index")
+             .long("index")
+             .requires("input")
+             .conflicts_with_all(&["shard", "update", "inspect", "to-propdump", "filter", "pack"])
+             .help("Writes an index of where every cell's records are next to each --input propdump, as FILE.cells, instead of creating cache files. When selecting with --teleports, indexed propdumps are read by seeking straight to the records needed. Only uncompressed propdumps can be indexed"))
+         .arg(Arg::with_name("stats")
+             .long("stats")
+             .conflicts_with_all(&["shard", "update", "inspect", "to-propdump", "filter", "pack", "index"])
+             .help("Reports on the selected objects instead of creating cache files: object counts and bytes per citizen with the cells they span, the densest cells, object types, the time range, the most used models, and how big cell.dat would get"))
+         .arg(Arg::with_name("dry-run")
+             .long("dry-run")
+             .conflicts_with_all(&["shard", "update", "inspect", "to-propdump", "filter", "pack", "index", "stats"])
+             .help("Goes through the conversion without creating cache files, then reports how many cells, objects and bytes would have been written and how big cell.dat would get, with a warning if that is over the 2GB AW limit"))
+         .arg(Arg::with_name("recover")
+             .long("recover")
+             .help("Skips damaged records instead of stopping at the first one. Each skipped stretch is reported, and the number of records lost is reported at the end"))
+         .arg(Arg::with_name("threads")
+             .long("threads")
+             .short("j")
+             .takes_value(true)
+             .value_name("THREADS")
+             .help("How many threads to decode and select objects with. Defaults to the number of processors"))
+         .arg(Arg::with_name("inspect")
+             .long("inspect")
+             .short("i")
+             .help("Lists every cell and object in the existing cell.idx and cell.dat instead of converting a propdump"))
+         .arg(Arg::with_name("to-propdump")
+             .long("to-propdump")
+             .short("p")
+             .takes_value(true)
+             .value_name("VERSION")
+             .possible_values(&["3", "4", "5"])
+             .conflicts_with("inspect")
+             .help("Converts the existing cell.idx and cell.dat back into a propdump of the given version, written to standard output"))
          .get_matches();
     let mut config = Config {
+        inputs: matches.values_of("input").map(|inputs| inputs.map(String::from).collect()).unwrap_or_default(),
+        inspect: matches.is_present("inspect"),
+        filter: matches.is_present("filter"),
+        pack: matches.value_of("pack").map(String::from),
+        index: matches.is_present("index"),
+        stats: matches.is_present("stats"),
+        dry_run: matches.is_present("dry-run"),
+        recover: matches.is_present("recover"),
+        to_propdump: None,
+        shard_region: None,
+        update: None,
+        memory_budget: 0,
+        downgrade: None,
+        long_fields: LongFields::Truncate,
+        code_page: CodePage::from_label(matches.value_of("code-page").unwrap(), matches.is_present("transliterate"))?,
+        input_encoding: None,
+        threads: 0,
         teleports: None,
+        budget: None,
         citnums: None,
         teleport_appender: None
     };
+    if let Some(version) = matches.value_of("to-propdump") {
+        config.to_propdump = Some(u8::from_str(version)?);
+    }
+    if let Some(region) = matches.value_of("shard") {
+        config.shard_region = Some(i16::from_str(region)?);
+        ensure!(config.shard_region > Some(0), "Shard region size must be at least 1 cell");
+    }
+    config.update = match matches.value_of("update") {
+        Some("append") => Some(UpdateMode::Append),
+        Some("replace") => Some(UpdateMode::Replace),
+        Some("dedupe") => Some(UpdateMode::Dedupe),
+        _ => None
+    };
+    config.memory_budget = usize::from_str(matches.value_of("memory").unwrap())? * 1024 * 1024;
+    if matches.is_present("downgrade") {
+        config.downgrade = Some(Downgrade::new(matches.value_of("marker").map(String::from)));
+    }
+    config.long_fields = match matches.value_of("long-fields") {
+        Some("drop") => LongFields::Drop,
+        Some("fail") => LongFields::Fail,
+        _ => LongFields::Truncate
+    };
+    if let Some(label) = matches.value_of("input-encoding") {
+        config.input_encoding = Some(codepage::lookup(label)?);
+    }
+    config.threads = match matches.value_of("threads") {
+        Some(threads) => usize::from_str(threads)?,
+        None => thread::available_parallelism().map(usize::from).unwrap_or(1)
+    };
+    ensure!(config.threads > 0, "At least 1 thread is needed");
     if let Some(teleport_file_name) = matches.value_of("teleports") {
-        let radius: i16 = i16::from_str(matches.value_of("radius").unwrap())?;
-        config.teleports = Some(Teleports::from_file(teleport_file_name, radius)?);
+        let radius = match matches.value_of("radius") {
+            Some(radius) => Some(i16::from_str(radius)?),
+            None => None
+        };
+        if let Some(budget) = matches.value_of("budget") {
+            config.budget = Some(Budget {
+                teleports: teleports::read_file(teleport_file_name)?,
+                bytes: u64::from_str(budget)? * 1024 * 1024,
+                max_radius: radius,
+                growth: if matches.value_of("grow") == Some("even") { Growth::Even } else { Growth::Priority }
+            });
+        } else {
+            let radius = radius.ok_or_else(|| format_err!("Teleports need a radius or a budget"))?;
+            config.teleports = Some(Teleports::from_file(teleport_file_name, radius)?);
+        }
     }
     if let Some(citnums) = matches.values_of("citnum") {
         config.citnums = Some(citnums.map(i32::from_str).map(Result::unwrap).collect());
@@ -148,45 +405,365 @@ fn config() -> Result<Config, failure::Error> {
     Ok(config)
 }
 
-fn main() -> Result<(), failure::Error> {
-    use std::fs;
-    use std::io;
-    ctrlc::set_handler(move || {
-        println!("Received Ctrl-C");
-        RUNNING.store(false, Ordering::SeqCst);
-    })?;
-    let mut config = config()?;
-    fs::copy("blank42.dat", "cell.dat")?;
-    fs::copy("blank42.idx", "cell.idx")?;
-    ctree::init()?;
-    let dat = ctree::DatFile::open("cell.dat")?;
-    let idx = ctree::IdxFile::open("cell.idx")?;
-    let stdin = io::stdin();
-    let propdump_file = stdin.lock();
-    let propdump = propdump::Propdump::new(propdump_file)?.filter(|obj| {
-        if let Some(ref teleports) = config.teleports {
+impl Config {
+    /// Whether the selection options keep an object
+    fn select(&self, obj: &aw::Object) -> bool {
+        if let Some(ref teleports) = self.teleports {
             if !teleports.contains(obj) {
                 return false;
             }
         }
-        if let Some(ref citnums) = config.citnums {
+        if let Some(ref citnums) = self.citnums {
             if !citnums.contains(&obj.citnum) {
                 return false;
             }
         }
-        if let Some(ref mut teleport_appender) = config.teleport_appender {
-            teleport_appender.check_to_append(&obj).expect("Unable to append to teleport append");
-        }
         true
-    });
-    let mut writer = ObjectWriter::new(&idx, &dat);
-    for object in propdump {
+    }
+}
+
+fn inspect(code_page: &CodePage) -> Result<(), failure::Error> {
+    let cache = Cache::open("cell")?;
+    for cell in cache.cells() {
+        let cell = cell?;
+        if cell.key.kind != cache::CELL_DATA {
+            let key: String = cell.key.to_bytes().iter().flat_map(|&byte| ascii::escape_default(byte)).map(char::from).collect();
+            println!("Key \"{}\": {} bytes", key, cell.data.len());
+            continue;
+        }
+        let objects = cell.objects(code_page)?;
+        println!("Cell {} {}: {} objects", cell.key.cell_x, cell.key.cell_z, objects.len());
+        for obj in objects {
+            println!("    {} {} {} {} {} {} {} {} {} {:?} {:?} {:?} {} bytes of data", obj.citnum, obj.time, obj.x, obj.y, obj.z, obj.yaw, obj.tilt, obj.roll, obj.type_, obj.name, obj.desc, obj.action, obj.data.len());
+            match obj.kind(code_page) {
+                ObjectKind::Other { .. } => (),
+                kind => println!("        {:?}", kind)
+            }
+        }
+    }
+    Ok(())
+}
+
+fn to_propdump(version: u8, code_page: CodePage) -> Result<(), failure::Error> {
+    let cache = Cache::open("cell")?;
+    let stdout = io::stdout();
+    let mut out = propdump::PropdumpWriter::new(BufWriter::new(stdout.lock()), version)?;
+    cache.write_propdump(&mut out, code_page)?;
+    out.flush()?;
+    out.report();
+    Ok(())
+}
+
+/// Where objects are read from
+enum Source {
+    Propdump(Input),
+    Pack(Pack),
+    Indexed(IndexedPropdump)
+}
+
+impl Source {
+    /// Version of the propdump the objects come from
+    fn version(&self) -> u8 {
+        match self {
+            Source::Propdump(input) => input.propdump.version(),
+            Source::Pack(pack) => pack.version(),
+            Source::Indexed(dump) => dump.version()
+        }
+    }
+
+    /// Encoding of the propdump the objects come from
+    fn encoding(&self) -> EncodingRef {
+        match self {
+            Source::Propdump(input) => input.propdump.encoding(),
+            Source::Pack(pack) => pack.encoding(),
+            Source::Indexed(dump) => dump.encoding()
+        }
+    }
+}
+
+/// Applies `--input-encoding` to a version 3 or 4 propdump, or reports the encoding it was found to be in
+fn set_input_encoding<R: BufRead>(name: &str, propdump: &mut Propdump<R>, config: &Config) {
+    if propdump.version() >= 5 {
+        return;
+    }
+    match config.input_encoding {
+        Some(encoding) => propdump.set_encoding(encoding),
+        None => {
+            let (detected, default) = (propdump.encoding(), propdump.schema().encoding);
+            if detected.name() != default.name() {
+                eprintln!("{}: reading the text as {}, as it does not look like {}. Use --input-encoding to choose another",
+                          name, detected.whatwg_name().unwrap_or_else(|| detected.name()), default.whatwg_name().unwrap_or_else(|| default.name()));
+            }
+        }
+    }
+}
+
+fn open_sources(config: &Config) -> Result<Vec<Source>, failure::Error> {
+    let mut sources = Vec::new();
+    if config.inputs.is_empty() {
+        let mut propdump = Propdump::new(input::decompress(BufReader::new(io::stdin()))?)?;
+        propdump.set_recover(config.recover);
+        set_input_encoding("standard input", &mut propdump, config);
+        sources.push(Source::Propdump(Input { name: "standard input".to_string(), propdump }));
+    }
+    for path in &config.inputs {
+        if pack::is_pack(path).map_err(|err| format_err!("Unable to open {}: {}", path, err))? {
+            sources.push(Source::Pack(Pack::open(path)?));
+            continue;
+        }
+        // Seeking only pays off when most of the propdump can be left out
+        if config.teleports.is_some() {
+            if let Some(mut dump) = IndexedPropdump::open(path)? {
+                set_input_encoding(path, dump.propdump_mut(), config);
+                sources.push(Source::Indexed(dump));
+                continue;
+            }
+        }
+        let mut propdump = Propdump::new(input::open(path)?).map_err(|err| format_err!("{}: {}", path, err))?;
+        propdump.set_recover(config.recover);
+        set_input_encoding(path, &mut propdump, config);
+        sources.push(Source::Propdump(Input { name: path.clone(), propdump }));
+    }
+    Ok(sources)
+}
+
+/// The cells of a cell table that the teleport areas cover, or all of them
+fn selected_cells(cells: &[CellEntry], config: &Config) -> Vec<CellEntry> {
+    let mut selected = match config.teleports {
+        Some(ref teleports) => teleports.regions().flat_map(|(x, z)| pack::cells_in(cells, x, z)).collect(),
+        None => cells.to_vec()
+    };
+    selected.sort_by_key(|cell| (cell.cell_x, cell.cell_z));
+    selected.dedup_by_key(|cell| (cell.cell_x, cell.cell_z));
+    selected
+}
+
+/// Hands the selected objects of the given cells, as read by `read_cell`, to `f`
+fn read_cells<R, F>(cells: Vec<CellEntry>, mut read_cell: R, config: &Config, mut appender: Option<&mut TeleportAppender>, f: &mut F) -> Result<(), failure::Error>
+    where R: FnMut(&CellEntry) -> Result<Vec<aw::Object>, failure::Error>,
+          F: FnMut(&aw::Object) -> Result<(), failure::Error> {
+    let scanner = appender.as_ref().map(|appender| appender.scanner());
+    for cell in cells {
+        for object in read_cell(&cell)? {
+            if !RUNNING.load(Ordering::SeqCst) {
+                eprintln!("Quitting due to Ctrl-C");
+                return Ok(());
+            }
+            if !config.select(&object) {
+                continue;
+            }
+            if let (Some(appender), Some(scanner)) = (appender.as_mut(), scanner.as_ref()) {
+                appender.append(&scanner.scan(&object)).expect("Unable to append to teleport append");
+            }
+            f(&object)?;
+        }
+    }
+    Ok(())
+}
+
+/// Hands every selected object of the sources to `f`, until done or Ctrl-C is pressed
+fn for_each_object<F>(sources: Vec<Source>, config: &mut Config, mut f: F) -> Result<(), failure::Error>
+    where F: FnMut(&aw::Object) -> Result<(), failure::Error> {
+    let mut appender = config.teleport_appender.take();
+    let mut lost = 0;
+    for source in sources {
         if !RUNNING.load(Ordering::SeqCst) {
-            println!("Quitting due to Ctrl-C");
             break;
         }
-        writer.add_object(&object)?;
+        match source {
+            Source::Propdump(input) => {
+                lost += pipeline::run(vec![input], config.threads, |obj| config.select(obj), appender.as_mut(), &mut f)?;
+            },
+            Source::Pack(mut pack) => {
+                let cells = selected_cells(pack.cells(), config);
+                read_cells(cells, |cell| pack.read_cell(cell), config, appender.as_mut(), &mut f)?;
+            },
+            Source::Indexed(mut dump) => {
+                let cells = selected_cells(dump.cells(), config);
+                read_cells(cells, |cell| dump.read_cell(cell), config, appender.as_mut(), &mut f)?;
+            }
+        }
     }
-    
+    if config.recover {
+        eprintln!("{} damaged records skipped", lost);
+    }
+    Ok(())
+}
+
+/// Measures the cells of the inputs and grows the teleport areas to fit the budget.
+/// Returns false if Ctrl-C was pressed before the areas were worked out.
+fn choose_regions(budget: &Budget, config: &mut Config) -> Result<bool, failure::Error> {
+    ensure!(!config.inputs.is_empty(), "A budget needs the propdump to be read twice, so give it with --input rather than on standard input");
+    let sources = open_sources(config)?;
+    let appender = config.teleport_appender.take();
+    let mut sizes = CellSizes::default();
+    let mut downgrade = config.downgrade.as_ref().map(Downgrade::quiet);
+    let mut limits = FieldLimits::quiet(config.long_fields, config.code_page);
+    let mut object_buffer = Vec::new();
+    let measured = for_each_object(sources, config, |object| {
+        let (object, text) = match fit(object, downgrade.as_mut(), &mut limits)? {
+            Some(fitted) => fitted,
+            None => return Ok(())
+        };
+        let loc = object.location();
+        object_buffer.clear();
+        object.write_encoded(&mut object_buffer, &text)?;
+        sizes.add(loc.cell_x, loc.cell_z, object_buffer.len() as u64);
+        Ok(())
+    });
+    config.teleport_appender = appender;
+    measured?;
+    if !RUNNING.load(Ordering::SeqCst) {
+        return Ok(false);
+    }
+    let template = cache::estimate_dat_size(0, 0);
+    let radii = budget::grow(&budget.teleports, &sizes, budget.bytes.saturating_sub(template), budget.max_radius, budget.growth);
+    for (teleport, radius) in budget.teleports.iter().zip(&radii) {
+        match radius {
+            Some(radius) => println!("Teleport {}: radius {}", teleport.coords, radius),
+            None => println!("Teleport {}: left out, its own cell does not fit", teleport.coords)
+        }
+    }
+    config.teleports = Some(Teleports::around(budget.teleports.iter().zip(radii).filter_map(|(teleport, radius)| radius.map(|radius| (teleport, radius)))));
+    Ok(true)
+}
+
+/// The version and encoding the sources share, which a propdump or pack of their objects is written in.
+/// Sources that differ are refused, as writing them in another version or encoding would lose types, data or
+/// characters.
+fn common_format(sources: &[Source], config: &Config) -> Result<(u8, EncodingRef), failure::Error> {
+    let names: Vec<&str> = if config.inputs.is_empty() { vec!["standard input"] } else { config.inputs.iter().map(String::as_str).collect() };
+    let (version, encoding) = (sources[0].version(), sources[0].encoding());
+    for (source, name) in sources.iter().zip(&names).skip(1) {
+        if source.version() != version || source.encoding().name() != encoding.name() {
+            bail!("{} is a version {} propdump in {}, but {} is a version {} propdump in {}. They cannot be written out together without losing anything",
+                  names[0], version, encoding.name(), name, source.version(), source.encoding().name());
+        }
+    }
+    Ok((version, encoding))
+}
+
+fn filter(sources: Vec<Source>, config: &mut Config) -> Result<(), failure::Error> {
+    let (version, encoding) = common_format(&sources, config)?;
+    let stdout = io::stdout();
+    let mut out = propdump::PropdumpWriter::new(BufWriter::new(stdout.lock()), version)?;
+    // Keeps the text as it was, whatever code page it is in
+    out.set_encoding(encoding);
+    for_each_object(sources, config, |object| out.write(object))?;
+    out.flush()?;
+    out.report();
     Ok(())
 }
+
+fn stats(sources: Vec<Source>, config: &mut Config) -> Result<(), failure::Error> {
+    let types = sources.iter().any(|source| Schema::of(source.version()).is_some_and(|schema| schema.type_));
+    let mut stats = Stats::default();
+    let mut downgrade = config.downgrade.clone();
+    // A report only reads, so fields too long for the cache are counted without stopping
+    let mut limits = FieldLimits::quiet(config.long_fields, config.code_page);
+    for_each_object(sources, config, |object| match fit(object, downgrade.as_mut(), &mut limits)? {
+        Some((object, text)) => stats.add(&object, &text),
+        None => Ok(())
+    })?;
+    if let Some(ref downgrade) = downgrade {
+        downgrade.report();
+    }
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    stats.report(&mut out, types)?;
+    if limits.truncated > 0 || limits.dropped > 0 {
+        writeln!(out, "\n{} objects would be truncated and {} left out for having fields too long for the cache", limits.truncated, limits.dropped)?;
+    }
+    if limits.lossy > 0 {
+        writeln!(out, "\n{} objects have characters {} lacks, which would be written as '?'", limits.lossy, config.code_page.name())?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn dry_run(sources: Vec<Source>, config: &mut Config) -> Result<(), failure::Error> {
+    let mut dry_run = DryRun::default();
+    let mut downgrade = config.downgrade.clone();
+    let mut limits = FieldLimits::new(config.long_fields, config.code_page);
+    let mut object_buffer = Vec::new();
+    let mut objects = 0u64;
+    for_each_object(sources, config, |object| {
+        let (object, text) = match fit(object, downgrade.as_mut(), &mut limits)? {
+            Some(fitted) => fitted,
+            None => return Ok(())
+        };
+        let loc = object.location();
+        object_buffer.clear();
+        object.write_encoded(&mut object_buffer, &text)?;
+        dry_run.add(loc.cell_x, loc.cell_z, object_buffer.len());
+        objects += 1;
+        Ok(())
+    })?;
+    if let Some(ref downgrade) = downgrade {
+        downgrade.report();
+    }
+    limits.report();
+    let (cells, bytes) = (dry_run.cells(), dry_run.bytes());
+    let overhead = cells * ctree::RECORD_OVERHEAD;
+    let size = cache::estimate_dat_size(cells, bytes);
+    println!("{} objects in {} cells: {} bytes of object data and {} bytes of record headers", objects, cells, bytes, overhead);
+    println!("Estimated cell.dat size: {} bytes", size);
+    if size > ctree::DAT_LIMIT as u64 {
+        eprintln!("Warning: cell.dat would go over the 2GB AW limit. Select fewer objects, or use --shard");
+    }
+    Ok(())
+}
+
+fn write_pack(path: &str, sources: Vec<Source>, config: &mut Config) -> Result<(), failure::Error> {
+    let (version, encoding) = common_format(&sources, config)?;
+    let mut writer = PackWriter::new(path, version, encoding, config.memory_budget);
+    for_each_object(sources, config, |object| writer.add(object))?;
+    writer.finish()
+}
+
+fn main() -> Result<(), failure::Error> {
+    ctrlc::set_handler(move || {
+        eprintln!("Received Ctrl-C");
+        RUNNING.store(false, Ordering::SeqCst);
+    })?;
+    let mut config = config()?;
+    ctree::init()?;
+    if config.inspect {
+        return inspect(&config.code_page);
+    }
+    if let Some(version) = config.to_propdump {
+        return to_propdump(version, config.code_page);
+    }
+    if config.index {
+        for path in &config.inputs {
+            dumpindex::build(path, config.recover, config.memory_budget)?;
+        }
+        return Ok(());
+    }
+    if let Some(budget) = config.budget.take() {
+        if !choose_regions(&budget, &mut config)? {
+            return Ok(());
+        }
+    }
+    let sources = open_sources(&config)?;
+    if config.filter {
+        return filter(sources, &mut config);
+    }
+    if config.stats {
+        return stats(sources, &mut config);
+    }
+    if let Some(path) = config.pack.take() {
+        return write_pack(&path, sources, &mut config);
+    }
+    if config.dry_run {
+        return dry_run(sources, &mut config);
+    }
+    let sink: Box<dyn CellSink> = match (config.shard_region, config.update) {
+        (Some(region), _) => Box::new(ShardedCache::new("cell", region)?),
+        (None, Some(mode)) => Box::new(UpdatedCache::new(Cache::open("cell")?, mode, config.code_page)),
+        (None, None) => Box::new(Cache::create("cell")?)
+    };
+    let mut writer = ObjectWriter::new(sink, config.memory_budget, config.downgrade.take(), config.long_fields, config.code_page);
+    for_each_object(sources, &mut config, |object| writer.add_object(object))?;
+    writer.finish()
+}
@@ -0,0 +1,565 @@
+//! Typed object data. Objects of AW 4 and later carry binary data whose layout depends on their type: the bounds
+//! and properties of zones, the settings of particle emitters, the parameters and waypoints of movers, and the
+//! parameters of cameras.
+//!
+//! The layouts follow the packed `aw_object_data_zone`, `aw_object_data_particles`, `aw_object_data_mover` and
+//! `aw_object_data_camera` structures of aw.h in the AW SDK: little endian numbers, floats as f32, a u8 length for
+//! each string before the strings themselves. Data that does not parse, or that would not be written back byte
+//! for byte, is kept as it is in `ObjectKind::Other`, so parsing and writing always gives back the same data.
+
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+
+use std::fmt;
+use std::io::{self, Cursor, Read, Write};
+
+use aw::Object;
+use codepage::CodePage;
+
+/// Object types, as in the `type` field of version 4 and 5 propdumps
+pub const TYPE_UNKNOWN: i32 = 0;
+pub const TYPE_V3: i32 = 1;
+pub const TYPE_V4: i32 = 2;
+pub const TYPE_ZONE: i32 = 3;
+pub const TYPE_PARTICLES: i32 = 4;
+pub const TYPE_MOVER: i32 = 5;
+pub const TYPE_CAMERA: i32 = 6;
+
+/// What an object type is called, for reports
+pub fn type_name(type_: i32) -> Option<&'static str> {
+    Some(match type_ {
+        TYPE_UNKNOWN => "unknown",
+        TYPE_V3 => "v3 object",
+        TYPE_V4 => "v4 object",
+        TYPE_ZONE => "zone",
+        TYPE_PARTICLES => "particle emitter",
+        TYPE_MOVER => "mover",
+        TYPE_CAMERA => "camera",
+        _ => return None
+    })
+}
+
+/// A string in object data, as stored, along with what it says in the code page of the propdump or cache
+/// the object came from
+#[derive(Clone, PartialEq, Eq, Default)]
+pub struct Text {
+    pub bytes: Vec<u8>,
+    pub text: String
+}
+
+impl fmt::Debug for Text {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.text)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct Vector {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32
+}
+
+/// A range particles take random values from
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct VectorRange {
+    pub min: Vector,
+    pub max: Vector
+}
+
+/// `aw_object_data_zone`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Zone {
+    /// Extent of the zone around the object
+    pub size: Vector,
+    pub shape: u8,
+    /// Which of several overlapping zones applies
+    pub priority: u8,
+    pub gravity: f32,
+    pub friction: f32,
+    pub flags: u32,
+    /// Fog color, as 0xBBGGRR
+    pub color: u32,
+    pub fog_min: u16,
+    pub fog_max: u16,
+    pub footstep: Text,
+    pub ambient: Text,
+    pub camera: Text,
+    pub target_cursor: Text,
+    pub voip_rights: Text,
+    pub name: Text
+}
+
+/// `aw_object_data_particles`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Particles {
+    pub volume: VectorRange,
+    pub speed: VectorRange,
+    pub accel: VectorRange,
+    pub angle: VectorRange,
+    pub spin: VectorRange,
+    pub size: VectorRange,
+    /// Milliseconds between releases
+    pub release_min: u32,
+    pub release_max: u32,
+    /// Particles per release
+    pub release_size: u16,
+    /// Milliseconds
+    pub lifespan: u32,
+    pub emitter_lifespan: u32,
+    pub fade_in: u32,
+    pub fade_out: u32,
+    /// Colors, as 0xBBGGRR
+    pub color_start: u32,
+    pub color_end: u32,
+    pub opacity: f32,
+    pub render_style: u8,
+    pub flags: u16,
+    /// Sprite, facer, model and so on
+    pub style: u8,
+    pub asset_list: Text,
+    pub name: Text
+}
+
+/// A point a mover travels through, relative to the mover
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct Waypoint {
+    pub position: Vector
+}
+
+/// `aw_object_data_mover`, followed by its waypoints
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Mover {
+    pub mover_type: u8,
+    pub flags: u16,
+    pub locked_position: u8,
+    pub locked_yaw: u8,
+    pub glide_factor: f32,
+    pub speed_factor: f32,
+    pub friction: f32,
+    pub accel_tilt_x: f32,
+    pub accel_tilt_z: f32,
+    pub turn_factor: f32,
+    pub name: Text,
+    pub sequence: Text,
+    pub script: Text,
+    pub sound: Text,
+    pub bump_name: Text,
+    pub waypoints: Vec<Waypoint>
+}
+
+/// `aw_object_data_camera`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Camera {
+    pub flags: u16,
+    pub zoom: f32,
+    pub name: Text
+}
+
+/// The type of an object along with its data
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObjectKind {
+    Zone(Zone),
+    Particles(Particles),
+    Mover(Mover),
+    Camera(Camera),
+    /// Any other type, and data of the types above that could not be parsed, as is
+    Other {
+        type_: i32,
+        data: Vec<u8>
+    }
+}
+
+impl ObjectKind {
+    /// Parses the data of an object of the given type, with strings in the given code page
+    pub fn parse(type_: i32, data: &[u8], code_page: &CodePage) -> ObjectKind {
+        let mut r = Cursor::new(data);
+        let parsed = match type_ {
+            TYPE_ZONE => read_zone(&mut r, code_page).map(ObjectKind::Zone),
+            TYPE_PARTICLES => read_particles(&mut r, code_page).map(ObjectKind::Particles),
+            TYPE_MOVER => read_mover(&mut r, code_page).map(ObjectKind::Mover),
+            TYPE_CAMERA => read_camera(&mut r, code_page).map(ObjectKind::Camera),
+            _ => Err(io::ErrorKind::InvalidData.into())
+        };
+        match parsed {
+            Ok(kind) if r.position() == data.len() as u64 && kind.data() == data => kind,
+            _ => ObjectKind::Other {
+                type_,
+                data: data.to_vec()
+            }
+        }
+    }
+
+    /// The object data, laid out as `parse` reads it
+    pub fn data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        // Writing to a Vec cannot fail, and strings too long for their length byte are cut short
+        let _ = match self {
+            ObjectKind::Zone(zone) => write_zone(&mut data, zone),
+            ObjectKind::Particles(particles) => write_particles(&mut data, particles),
+            ObjectKind::Mover(mover) => write_mover(&mut data, mover),
+            ObjectKind::Camera(camera) => write_camera(&mut data, camera),
+            ObjectKind::Other { data: other, .. } => data.write_all(other)
+        };
+        data
+    }
+}
+
+impl Object {
+    /// The type and data of the object, parsed, with strings in the code page the object came in
+    pub fn kind(&self, code_page: &CodePage) -> ObjectKind {
+        ObjectKind::parse(self.type_, &self.data, code_page)
+    }
+}
+
+fn read_vector<R: Read>(r: &mut R) -> io::Result<Vector> {
+    Ok(Vector {
+        x: r.read_f32::<LE>()?,
+        y: r.read_f32::<LE>()?,
+        z: r.read_f32::<LE>()?
+    })
+}
+
+fn write_vector<W: Write>(w: &mut W, vector: &Vector) -> io::Result<()> {
+    w.write_f32::<LE>(vector.x)?;
+    w.write_f32::<LE>(vector.y)?;
+    w.write_f32::<LE>(vector.z)
+}
+
+fn read_range<R: Read>(r: &mut R) -> io::Result<VectorRange> {
+    Ok(VectorRange {
+        min: read_vector(r)?,
+        max: read_vector(r)?
+    })
+}
+
+fn write_range<W: Write>(w: &mut W, range: &VectorRange) -> io::Result<()> {
+    write_vector(w, &range.min)?;
+    write_vector(w, &range.max)
+}
+
+/// Reads `N` strings: their u8 lengths, then the strings one after another
+fn read_strings<R: Read, const N: usize>(r: &mut R, code_page: &CodePage) -> io::Result<[Text; N]> {
+    let mut lens = [0u8; N];
+    r.read_exact(&mut lens)?;
+    let mut strings: [Text; N] = ::std::array::from_fn(|_| Text::default());
+    for (string, &len) in strings.iter_mut().zip(&lens) {
+        string.bytes.resize(len as usize, 0);
+        r.read_exact(&mut string.bytes)?;
+        string.text = code_page.decode(&string.bytes);
+    }
+    Ok(strings)
+}
+
+fn write_strings<W: Write>(w: &mut W, strings: &[&Text]) -> io::Result<()> {
+    let strings: Vec<&[u8]> = strings.iter().map(|string| &string.bytes[..string.bytes.len().min(u8::MAX as usize)]).collect();
+    for string in &strings {
+        w.write_u8(string.len() as u8)?;
+    }
+    for string in &strings {
+        w.write_all(string)?;
+    }
+    Ok(())
+}
+
+fn read_zone<R: Read>(r: &mut R, code_page: &CodePage) -> io::Result<Zone> {
+    let size = read_vector(r)?;
+    let shape = r.read_u8()?;
+    let priority = r.read_u8()?;
+    let gravity = r.read_f32::<LE>()?;
+    let friction = r.read_f32::<LE>()?;
+    let flags = r.read_u32::<LE>()?;
+    let color = r.read_u32::<LE>()?;
+    let fog_min = r.read_u16::<LE>()?;
+    let fog_max = r.read_u16::<LE>()?;
+    let [footstep, ambient, camera, target_cursor, voip_rights, name] = read_strings(r, code_page)?;
+    Ok(Zone { size, shape, priority, gravity, friction, flags, color, fog_min, fog_max, footstep, ambient, camera, target_cursor, voip_rights, name })
+}
+
+fn write_zone<W: Write>(w: &mut W, zone: &Zone) -> io::Result<()> {
+    write_vector(w, &zone.size)?;
+    w.write_u8(zone.shape)?;
+    w.write_u8(zone.priority)?;
+    w.write_f32::<LE>(zone.gravity)?;
+    w.write_f32::<LE>(zone.friction)?;
+    w.write_u32::<LE>(zone.flags)?;
+    w.write_u32::<LE>(zone.color)?;
+    w.write_u16::<LE>(zone.fog_min)?;
+    w.write_u16::<LE>(zone.fog_max)?;
+    write_strings(w, &[&zone.footstep, &zone.ambient, &zone.camera, &zone.target_cursor, &zone.voip_rights, &zone.name])
+}
+
+fn read_particles<R: Read>(r: &mut R, code_page: &CodePage) -> io::Result<Particles> {
+    let volume = read_range(r)?;
+    let speed = read_range(r)?;
+    let accel = read_range(r)?;
+    let angle = read_range(r)?;
+    let spin = read_range(r)?;
+    let size = read_range(r)?;
+    let release_min = r.read_u32::<LE>()?;
+    let release_max = r.read_u32::<LE>()?;
+    let release_size = r.read_u16::<LE>()?;
+    let lifespan = r.read_u32::<LE>()?;
+    let emitter_lifespan = r.read_u32::<LE>()?;
+    let fade_in = r.read_u32::<LE>()?;
+    let fade_out = r.read_u32::<LE>()?;
+    let color_start = r.read_u32::<LE>()?;
+    let color_end = r.read_u32::<LE>()?;
+    let opacity = r.read_f32::<LE>()?;
+    let render_style = r.read_u8()?;
+    let flags = r.read_u16::<LE>()?;
+    let style = r.read_u8()?;
+    let [asset_list, name] = read_strings(r, code_page)?;
+    Ok(Particles {
+        volume, speed, accel, angle, spin, size, release_min, release_max, release_size, lifespan, emitter_lifespan,
+        fade_in, fade_out, color_start, color_end, opacity, render_style, flags, style, asset_list, name
+    })
+}
+
+fn write_particles<W: Write>(w: &mut W, particles: &Particles) -> io::Result<()> {
+    for range in &[particles.volume, particles.speed, particles.accel, particles.angle, particles.spin, particles.size] {
+        write_range(w, range)?;
+    }
+    w.write_u32::<LE>(particles.release_min)?;
+    w.write_u32::<LE>(particles.release_max)?;
+    w.write_u16::<LE>(particles.release_size)?;
+    w.write_u32::<LE>(particles.lifespan)?;
+    w.write_u32::<LE>(particles.emitter_lifespan)?;
+    w.write_u32::<LE>(particles.fade_in)?;
+    w.write_u32::<LE>(particles.fade_out)?;
+    w.write_u32::<LE>(particles.color_start)?;
+    w.write_u32::<LE>(particles.color_end)?;
+    w.write_f32::<LE>(particles.opacity)?;
+    w.write_u8(particles.render_style)?;
+    w.write_u16::<LE>(particles.flags)?;
+    w.write_u8(particles.style)?;
+    write_strings(w, &[&particles.asset_list, &particles.name])
+}
+
+fn read_mover<R: Read>(r: &mut R, code_page: &CodePage) -> io::Result<Mover> {
+    let mover_type = r.read_u8()?;
+    let flags = r.read_u16::<LE>()?;
+    let locked_position = r.read_u8()?;
+    let locked_yaw = r.read_u8()?;
+    let glide_factor = r.read_f32::<LE>()?;
+    let speed_factor = r.read_f32::<LE>()?;
+    let friction = r.read_f32::<LE>()?;
+    let accel_tilt_x = r.read_f32::<LE>()?;
+    let accel_tilt_z = r.read_f32::<LE>()?;
+    let turn_factor = r.read_f32::<LE>()?;
+    let [name, sequence, script, sound, bump_name] = read_strings(r, code_page)?;
+    let count = r.read_u16::<LE>()?;
+    let mut waypoints = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        waypoints.push(Waypoint { position: read_vector(r)? });
+    }
+    Ok(Mover {
+        mover_type, flags, locked_position, locked_yaw, glide_factor, speed_factor, friction, accel_tilt_x,
+        accel_tilt_z, turn_factor, name, sequence, script, sound, bump_name, waypoints
+    })
+}
+
+fn write_mover<W: Write>(w: &mut W, mover: &Mover) -> io::Result<()> {
+    w.write_u8(mover.mover_type)?;
+    w.write_u16::<LE>(mover.flags)?;
+    w.write_u8(mover.locked_position)?;
+    w.write_u8(mover.locked_yaw)?;
+    w.write_f32::<LE>(mover.glide_factor)?;
+    w.write_f32::<LE>(mover.speed_factor)?;
+    w.write_f32::<LE>(mover.friction)?;
+    w.write_f32::<LE>(mover.accel_tilt_x)?;
+    w.write_f32::<LE>(mover.accel_tilt_z)?;
+    w.write_f32::<LE>(mover.turn_factor)?;
+    write_strings(w, &[&mover.name, &mover.sequence, &mover.script, &mover.sound, &mover.bump_name])?;
+    let waypoints = &mover.waypoints[..mover.waypoints.len().min(u16::MAX as usize)];
+    w.write_u16::<LE>(waypoints.len() as u16)?;
+    for waypoint in waypoints {
+        write_vector(w, &waypoint.position)?;
+    }
+    Ok(())
+}
+
+fn read_camera<R: Read>(r: &mut R, code_page: &CodePage) -> io::Result<Camera> {
+    let flags = r.read_u16::<LE>()?;
+    let zoom = r.read_f32::<LE>()?;
+    let [name] = read_strings(r, code_page)?;
+    Ok(Camera { flags, zoom, name })
+}
+
+fn write_camera<W: Write>(w: &mut W, camera: &Camera) -> io::Result<()> {
+    w.write_u16::<LE>(camera.flags)?;
+    w.write_f32::<LE>(camera.zoom)?;
+    write_strings(w, &[&camera.name])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Object data built field by field, as the browser lays it out
+    #[derive(Default)]
+    struct Sample(Vec<u8>);
+
+    impl Sample {
+        fn u8(mut self, value: u8) -> Self {
+            self.0.push(value);
+            self
+        }
+
+        fn u16(mut self, value: u16) -> Self {
+            self.0.extend_from_slice(&value.to_le_bytes());
+            self
+        }
+
+        fn u32(mut self, value: u32) -> Self {
+            self.0.extend_from_slice(&value.to_le_bytes());
+            self
+        }
+
+        fn f32(mut self, value: f32) -> Self {
+            self.0.extend_from_slice(&value.to_le_bytes());
+            self
+        }
+
+        fn vector(self, x: f32, y: f32, z: f32) -> Self {
+            self.f32(x).f32(y).f32(z)
+        }
+
+        fn strings(mut self, strings: &[&[u8]]) -> Self {
+            self.0.extend(strings.iter().map(|string| string.len() as u8));
+            for string in strings {
+                self.0.extend_from_slice(string);
+            }
+            self
+        }
+    }
+
+    fn text(text: &str) -> Text {
+        Text {
+            bytes: text.as_bytes().to_vec(),
+            text: text.to_string()
+        }
+    }
+
+    fn vector(x: f32, y: f32, z: f32) -> Vector {
+        Vector { x, y, z }
+    }
+
+    /// Parses the sample, checks it against `expected`, and checks it is written back byte for byte
+    fn round_trip(type_: i32, sample: Sample, expected: ObjectKind) {
+        let kind = ObjectKind::parse(type_, &sample.0, &CodePage::default());
+        assert_eq!(kind, expected);
+        assert_eq!(kind.data(), sample.0);
+    }
+
+    #[test]
+    fn zones_round_trip() {
+        let sample = Sample::default().vector(10.0, 2.5, -4.0).u8(1).u8(3).f32(0.5).f32(0.25).u32(0x0102_0304)
+            .u32(0x00FF_8000).u16(5).u16(300).strings(&[b"step.wav", b"", b"cam", b"", b"voip", b"Lobby"]);
+        round_trip(TYPE_ZONE, sample, ObjectKind::Zone(Zone {
+            size: vector(10.0, 2.5, -4.0),
+            shape: 1,
+            priority: 3,
+            gravity: 0.5,
+            friction: 0.25,
+            flags: 0x0102_0304,
+            color: 0x00FF_8000,
+            fog_min: 5,
+            fog_max: 300,
+            footstep: text("step.wav"),
+            ambient: text(""),
+            camera: text("cam"),
+            target_cursor: text(""),
+            voip_rights: text("voip"),
+            name: text("Lobby")
+        }));
+    }
+
+    #[test]
+    fn particles_round_trip() {
+        let mut sample = Sample::default();
+        for n in 0..6 {
+            let n = n as f32;
+            sample = sample.vector(n, n + 0.5, -n).vector(n * 2.0, 1.0, 0.0);
+        }
+        let sample = sample.u32(100).u32(200).u16(7).u32(3000).u32(0).u32(10).u32(20).u32(0xFFFFFF).u32(0x0000FF)
+            .f32(0.75).u8(2).u16(0x8001).u8(1).strings(&[b"spark1.jpg", b"fountain"]);
+        let range = |n: f32| VectorRange { min: vector(n, n + 0.5, -n), max: vector(n * 2.0, 1.0, 0.0) };
+        round_trip(TYPE_PARTICLES, sample, ObjectKind::Particles(Particles {
+            volume: range(0.0),
+            speed: range(1.0),
+            accel: range(2.0),
+            angle: range(3.0),
+            spin: range(4.0),
+            size: range(5.0),
+            release_min: 100,
+            release_max: 200,
+            release_size: 7,
+            lifespan: 3000,
+            emitter_lifespan: 0,
+            fade_in: 10,
+            fade_out: 20,
+            color_start: 0xFFFFFF,
+            color_end: 0x0000FF,
+            opacity: 0.75,
+            render_style: 2,
+            flags: 0x8001,
+            style: 1,
+            asset_list: text("spark1.jpg"),
+            name: text("fountain")
+        }));
+    }
+
+    #[test]
+    fn movers_round_trip() {
+        let sample = Sample::default().u8(2).u16(0x0011).u8(1).u8(0).f32(1.0).f32(2.0).f32(0.1).f32(0.2).f32(0.3).f32(4.0)
+            .strings(&[b"train", b"", b"", b"horn.wav", b"stop"]).u16(2).vector(0.0, 0.0, 0.0).vector(1.0, -2.0, 30.5);
+        round_trip(TYPE_MOVER, sample, ObjectKind::Mover(Mover {
+            mover_type: 2,
+            flags: 0x0011,
+            locked_position: 1,
+            locked_yaw: 0,
+            glide_factor: 1.0,
+            speed_factor: 2.0,
+            friction: 0.1,
+            accel_tilt_x: 0.2,
+            accel_tilt_z: 0.3,
+            turn_factor: 4.0,
+            name: text("train"),
+            sequence: text(""),
+            script: text(""),
+            sound: text("horn.wav"),
+            bump_name: text("stop"),
+            waypoints: vec![Waypoint { position: vector(0.0, 0.0, 0.0) }, Waypoint { position: vector(1.0, -2.0, 30.5) }]
+        }));
+    }
+
+    #[test]
+    fn cameras_round_trip() {
+        let sample = Sample::default().u16(3).f32(1.5).strings(&[b"overview"]);
+        round_trip(TYPE_CAMERA, sample, ObjectKind::Camera(Camera { flags: 3, zoom: 1.5, name: text("overview") }));
+    }
+
+    #[test]
+    fn data_without_the_layout_of_its_type_is_kept_as_is() {
+        let camera = Sample::default().u16(3).f32(1.5).strings(&[b"overview"]).0;
+        let code_page = CodePage::default();
+        for data in [camera[..camera.len() - 1].to_vec(), [&camera[..], &[0]].concat(), vec![]] {
+            let kind = ObjectKind::parse(TYPE_CAMERA, &data, &code_page);
+            assert_eq!(kind, ObjectKind::Other { type_: TYPE_CAMERA, data: data.clone() });
+            assert_eq!(kind.data(), data);
+        }
+        // A NaN float would not compare equal, but is written back with the same bits
+        let nan = Sample::default().u16(0).u32(0x7FC0_1234).strings(&[b""]).0;
+        assert_eq!(ObjectKind::parse(TYPE_CAMERA, &nan, &code_page).data(), nan);
+    }
+
+    #[test]
+    fn strings_are_decoded_in_the_code_page() {
+        let code_page = CodePage::from_label("windows-1251", false).unwrap();
+        let data = Sample::default().u16(0).f32(1.0).strings(&[b"\xcf\xf0\xe8\xe2\xe5\xf2"]).0;
+        match ObjectKind::parse(TYPE_CAMERA, &data, &code_page) {
+            ObjectKind::Camera(camera) => assert_eq!(format!("{:?}", camera.name), "\"Привет\""),
+            kind => panic!("Expected a camera, got {:?}", kind)
+        }
+    }
+}
@@ -0,0 +1,443 @@
+//! Pack files: objects stored by cell in a compact binary form, for extracting from repeatedly without
+//! reparsing a text propdump.
+//!
+//! All numbers are little endian. The file starts with a header:
+//!
+//! * 8 bytes: `P2C42PAK`
+//! * u16: format version, currently 1
+//! * u8: version of the propdump the objects came from
+//! * u8: length of the name of the encoding the propdump's text was in, 0 for the one of its version
+//! * u32: number of cells
+//! * u64: offset of the cell table
+//! * the name of the encoding, such as windows-1251, so the text can be written back out as it was
+//!
+//! Then comes a block for every cell: a u32 object count, a fixed size header for each object, and the
+//! string table the object headers point into. An object header is the citnum, time, x, y and z as i32s,
+//! the yaw, tilt and roll as i16s, 2 reserved bytes, the type as an i32, then offset and length u32 pairs for the
+//! name, description, action and data, relative to the start of the string table. Text is UTF-8.
+//!
+//! The cell table at the end has an entry for every cell, sorted by cell x then cell z: the cell x and z as i16s,
+//! the u32 object count, and the u64 offset and length of the cell's block.
+
+use failure;
+use byteorder::{ByteOrder, LE, ReadBytesExt, WriteBytesExt};
+use encoding::types::EncodingRef;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+
+use aw::Object;
+use cellsort::CellSorter;
+use codepage;
+use propdump::Schema;
+
+const MAGIC: &[u8; 8] = b"P2C42PAK";
+const FORMAT_VERSION: u16 = 1;
+const HEADER_LEN: u64 = 24;
+const OBJECT_HEADER_LEN: usize = 64;
+
+/// Whether the file at `path` is a pack file
+pub fn is_pack(path: &str) -> io::Result<bool> {
+    let mut magic = [0u8; 8];
+    let mut file = File::open(path)?;
+    let mut read = 0;
+    while read < magic.len() {
+        match file.read(&mut magic[read..])? {
+            0 => return Ok(false),
+            n => read += n
+        }
+    }
+    Ok(&magic == MAGIC)
+}
+
+fn write_object_header<W: Write>(mut w: W, object: &Object, offsets: [u32; 4], lens: [u32; 4]) -> io::Result<()> {
+    w.write_i32::<LE>(object.citnum)?;
+    w.write_i32::<LE>(object.time)?;
+    w.write_i32::<LE>(object.x)?;
+    w.write_i32::<LE>(object.y)?;
+    w.write_i32::<LE>(object.z)?;
+    w.write_i16::<LE>(object.yaw)?;
+    w.write_i16::<LE>(object.tilt)?;
+    w.write_i16::<LE>(object.roll)?;
+    w.write_u16::<LE>(0)?;
+    w.write_i32::<LE>(object.type_)?;
+    for (offset, len) in offsets.iter().zip(&lens) {
+        w.write_u32::<LE>(*offset)?;
+        w.write_u32::<LE>(*len)?;
+    }
+    Ok(())
+}
+
+/// Writes a pack file. Objects can be added in any order.
+pub struct PackWriter {
+    path: String,
+    version: u8,
+    encoding: String,
+    sorter: CellSorter,
+    buffer: Vec<u8>
+}
+
+impl PackWriter {
+    /// `version` and `encoding` are those of the propdump the objects come from
+    pub fn new(path: &str, version: u8, encoding: EncodingRef, memory_budget: usize) -> Self {
+        PackWriter {
+            path: path.to_string(),
+            version,
+            encoding: encoding.whatwg_name().unwrap_or_else(|| encoding.name()).to_string(),
+            sorter: CellSorter::new(".", memory_budget),
+            buffer: Vec::new()
+        }
+    }
+
+    pub fn add(&mut self, object: &Object) -> Result<(), failure::Error> {
+        // Gathered as an object header with its strings right after it, made into a string table in finish
+        let strings = [object.name.as_bytes(), object.desc.as_bytes(), object.action.as_bytes(), &object.data[..]];
+        let mut lens = [0u32; 4];
+        for (len, string) in lens.iter_mut().zip(&strings) {
+            *len = string.len() as u32;
+        }
+        self.buffer.clear();
+        write_object_header(&mut self.buffer, object, [0; 4], lens)?;
+        for string in &strings {
+            self.buffer.extend_from_slice(string);
+        }
+        let loc = object.location();
+        self.sorter.push((loc.cell_x, loc.cell_z), &self.buffer)
+    }
+
+    pub fn finish(self) -> Result<(), failure::Error> {
+        let header = CellFileHeader {
+            magic: MAGIC,
+            format: FORMAT_VERSION,
+            version: self.version,
+            flags: self.encoding.len() as u8,
+            rest: self.encoding.into_bytes()
+        };
+        let mut headers = Vec::new();
+        let mut strings = Vec::new();
+        write_cell_file(&self.path, header, self.sorter, |data, block| {
+            headers.clear();
+            strings.clear();
+            let mut objects = 0;
+            let mut pos = 0;
+            while pos < data.len() {
+                let header = &data[pos..pos + OBJECT_HEADER_LEN];
+                let mut offsets = [0u32; 4];
+                let mut lens = [0u32; 4];
+                headers.extend_from_slice(&header[..32]);
+                pos += OBJECT_HEADER_LEN;
+                for i in 0..4 {
+                    lens[i] = LE::read_u32(&header[36 + i * 8..]);
+                    offsets[i] = strings.len() as u32;
+                    strings.extend_from_slice(&data[pos..pos + lens[i] as usize]);
+                    pos += lens[i] as usize;
+                }
+                for (offset, len) in offsets.iter().zip(&lens) {
+                    headers.write_u32::<LE>(*offset)?;
+                    headers.write_u32::<LE>(*len)?;
+                }
+                objects += 1;
+            }
+            block.write_u32::<LE>(objects)?;
+            block.extend_from_slice(&headers);
+            block.extend_from_slice(&strings);
+            Ok(objects)
+        })?;
+        Ok(())
+    }
+}
+
+/// The start of the header of pack files and propdump indexes
+pub struct CellFileHeader {
+    pub magic: &'static [u8; 8],
+    pub format: u16,
+    /// Version of the propdump
+    pub version: u8,
+    /// The byte after the version, which the formats use differently
+    pub flags: u8,
+    /// What follows the offset of the cell table
+    pub rest: Vec<u8>
+}
+
+/// Writes a pack file or propdump index: the header, a block for every cell in the sorter, then the cell table.
+/// `block` is given a cell's data to make its block from, and gives back the count of objects in it.
+/// Returns the number of cells.
+pub fn write_cell_file<F>(path: &str, header: CellFileHeader, sorter: CellSorter, mut block: F) -> Result<usize, failure::Error>
+    where F: FnMut(&[u8], &mut Vec<u8>) -> Result<u32, failure::Error> {
+    let header_len = HEADER_LEN + header.rest.len() as u64;
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&vec![0; header_len as usize])?;
+    let mut table = Vec::new();
+    let mut offset = header_len;
+    let mut buffer = Vec::new();
+    sorter.finish(|cell_x, cell_z, data| {
+        buffer.clear();
+        let objects = block(data, &mut buffer)?;
+        file.write_all(&buffer)?;
+        let len = buffer.len() as u64;
+        table.push(CellEntry { cell_x, cell_z, objects, offset, len });
+        offset += len;
+        Ok(())
+    })?;
+    for entry in &table {
+        entry.write(&mut file)?;
+    }
+    // The header goes in last, so a file that was not finished is not taken for a whole one
+    let mut file = file.into_inner().map_err(|err| err.into_error())?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(header.magic)?;
+    file.write_u16::<LE>(header.format)?;
+    file.write_u8(header.version)?;
+    file.write_u8(header.flags)?;
+    file.write_u32::<LE>(table.len() as u32)?;
+    file.write_u64::<LE>(offset)?;
+    file.write_all(&header.rest)?;
+    Ok(table.len())
+}
+
+/// Where a cell is in a pack file, or in a propdump index
+#[derive(Debug, Copy, Clone)]
+pub struct CellEntry {
+    pub cell_x: i16,
+    pub cell_z: i16,
+    pub objects: u32,
+    /// Where the cell's block starts in the file
+    pub offset: u64,
+    pub len: u64
+}
+
+impl CellEntry {
+    pub const LEN: usize = 24;
+
+    pub fn write<W: Write>(&self, mut w: W) -> io::Result<()> {
+        w.write_i16::<LE>(self.cell_x)?;
+        w.write_i16::<LE>(self.cell_z)?;
+        w.write_u32::<LE>(self.objects)?;
+        w.write_u64::<LE>(self.offset)?;
+        w.write_u64::<LE>(self.len)
+    }
+
+    /// Reads a whole cell table
+    pub fn read_table<R: Read>(mut r: R, count: u32) -> io::Result<Vec<CellEntry>> {
+        let mut table = vec![0; count as usize * CellEntry::LEN];
+        r.read_exact(&mut table)?;
+        Ok(table.chunks(CellEntry::LEN).map(|entry| CellEntry {
+            cell_x: LE::read_i16(&entry[0..2]),
+            cell_z: LE::read_i16(&entry[2..4]),
+            objects: LE::read_u32(&entry[4..8]),
+            offset: LE::read_u64(&entry[8..16]),
+            len: LE::read_u64(&entry[16..24])
+        }).collect())
+    }
+}
+
+/// The cells of a table sorted by cell x then cell z that are within an area, in the same order
+pub fn cells_in(cells: &[CellEntry], x: RangeInclusive<i16>, z: RangeInclusive<i16>) -> Vec<CellEntry> {
+    let mut found = Vec::new();
+    let start = cells.partition_point(|cell| cell.cell_x < *x.start());
+    let mut i = start;
+    while i < cells.len() && cells[i].cell_x <= *x.end() {
+        let cell_x = cells[i].cell_x;
+        i += cells[i..].partition_point(|cell| cell.cell_x == cell_x && cell.cell_z < *z.start());
+        while i < cells.len() && cells[i].cell_x == cell_x && cells[i].cell_z <= *z.end() {
+            found.push(cells[i]);
+            i += 1;
+        }
+        i += cells[i..].partition_point(|cell| cell.cell_x == cell_x);
+    }
+    found
+}
+
+/// A pack file opened for reading, with its cell table in memory
+pub struct Pack {
+    file: File,
+    version: u8,
+    encoding: EncodingRef,
+    cells: Vec<CellEntry>
+}
+
+impl Pack {
+    pub fn open(path: &str) -> Result<Self, failure::Error> {
+        let mut file = File::open(path)?;
+        let mut magic = [0u8; 8];
+        file.read_exact(&mut magic)?;
+        ensure!(&magic == MAGIC, "{} is not a pack file", path);
+        let format = file.read_u16::<LE>()?;
+        ensure!(format == FORMAT_VERSION, "{} is a version {} pack file, only version {} is supported", path, format, FORMAT_VERSION);
+        let version = file.read_u8()?;
+        let schema = match Schema::of(version) {
+            Some(schema) => schema,
+            None => bail!("{} holds objects from a version {} propdump, which is not a known version", path, version)
+        };
+        let mut encoding = vec![0; file.read_u8()? as usize];
+        let count = file.read_u32::<LE>()?;
+        let table_offset = file.read_u64::<LE>()?;
+        file.read_exact(&mut encoding)?;
+        let encoding = match encoding.len() {
+            0 => schema.encoding,
+            _ => codepage::lookup(&String::from_utf8_lossy(&encoding)).map_err(|err| format_err!("{}: {}", path, err))?
+        };
+        file.seek(SeekFrom::Start(table_offset))?;
+        let cells = CellEntry::read_table(&mut file, count)?;
+        Ok(Pack {
+            file,
+            version,
+            encoding,
+            cells
+        })
+    }
+
+    /// Version of the propdump the objects came from
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Encoding of the propdump the objects came from
+    pub fn encoding(&self) -> EncodingRef {
+        self.encoding
+    }
+
+    /// Every cell, sorted by cell x then cell z
+    pub fn cells(&self) -> &[CellEntry] {
+        &self.cells
+    }
+
+    pub fn read_cell(&mut self, cell: &CellEntry) -> Result<Vec<Object>, failure::Error> {
+        let mut block = vec![0; cell.len as usize];
+        self.file.seek(SeekFrom::Start(cell.offset))?;
+        self.file.read_exact(&mut block)?;
+        ensure!(block.len() >= 4, "Damaged pack file: cell {} {} is too short", cell.cell_x, cell.cell_z);
+        let count = LE::read_u32(&block[0..4]) as usize;
+        ensure!(block.len() >= 4 + count * OBJECT_HEADER_LEN, "Damaged pack file: cell {} {} is too short", cell.cell_x, cell.cell_z);
+        let strings = &block[4 + count * OBJECT_HEADER_LEN..];
+        let string = |header: &[u8], i: usize| -> Result<&[u8], failure::Error> {
+            let offset = LE::read_u32(&header[32 + i * 8..]) as usize;
+            let len = LE::read_u32(&header[36 + i * 8..]) as usize;
+            strings.get(offset..offset + len).ok_or_else(|| format_err!("Damaged pack file: string out of bounds in cell {} {}", cell.cell_x, cell.cell_z))
+        };
+        let text = |header: &[u8], i: usize| -> Result<String, failure::Error> {
+            Ok(String::from_utf8(string(header, i)?.to_vec())?)
+        };
+        let mut objects = Vec::with_capacity(count);
+        for header in block[4..4 + count * OBJECT_HEADER_LEN].chunks(OBJECT_HEADER_LEN) {
+            objects.push(Object {
+                citnum: LE::read_i32(&header[0..4]),
+                time: LE::read_i32(&header[4..8]),
+                x: LE::read_i32(&header[8..12]),
+                y: LE::read_i32(&header[12..16]),
+                z: LE::read_i32(&header[16..20]),
+                yaw: LE::read_i16(&header[20..22]),
+                tilt: LE::read_i16(&header[22..24]),
+                roll: LE::read_i16(&header[24..26]),
+                type_: LE::read_i32(&header[28..32]),
+                name: text(header, 0)?,
+                desc: text(header, 1)?,
+                action: text(header, 2)?,
+                data: string(header, 3)?.to_vec(),
+                ..Object::default()
+            });
+        }
+        Ok(objects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use encoding::all::{WINDOWS_1251, WINDOWS_1252};
+
+    use super::*;
+
+    /// A pack file in the temporary directory, removed again when dropped
+    struct Scratch(String);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            Scratch(env::temp_dir().join(format!("propdump2cell42-{}-{}.pak", name, process::id())).to_str().unwrap().to_string())
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn object(n: i32, x: i32, z: i32) -> Object {
+        Object {
+            citnum: n,
+            time: 1000 + n,
+            x,
+            y: -n,
+            z,
+            yaw: 900,
+            tilt: -5,
+            roll: 3,
+            type_: n % 3,
+            name: format!("model{}.rwx", n),
+            desc: if n % 2 == 0 { "Привет".to_string() } else { String::new() },
+            action: format!("create name obj{}", n),
+            data: (0..n as u8).collect(),
+            ..Object::default()
+        }
+    }
+
+    #[test]
+    fn objects_read_back_by_cell() {
+        let scratch = Scratch::new("round-trip");
+        let objects: Vec<Object> = (0..20).map(|n| object(n, (n - 10) * 700, (n % 4 - 2) * 1000)).collect();
+        let mut writer = PackWriter::new(&scratch.0, 3, WINDOWS_1251, 1 << 20);
+        for object in objects.iter().rev() {
+            writer.add(object).unwrap();
+        }
+        writer.finish().unwrap();
+        assert!(is_pack(&scratch.0).unwrap());
+
+        let mut pack = Pack::open(&scratch.0).unwrap();
+        assert_eq!(pack.version(), 3);
+        assert_eq!(pack.encoding().name(), "windows-1251");
+        let cells = pack.cells().to_vec();
+        assert!(cells.windows(2).all(|pair| (pair[0].cell_x, pair[0].cell_z) < (pair[1].cell_x, pair[1].cell_z)));
+        assert_eq!(cells.iter().map(|cell| cell.objects).sum::<u32>(), 20);
+        let mut read = Vec::new();
+        for cell in &cells {
+            for object in pack.read_cell(cell).unwrap() {
+                let loc = object.location();
+                assert_eq!((loc.cell_x, loc.cell_z), (cell.cell_x, cell.cell_z));
+                read.push(object);
+            }
+        }
+        read.sort_by_key(|object| object.citnum);
+        assert_eq!(format!("{:?}", read), format!("{:?}", objects));
+
+        let x = cells.iter().map(|cell| cell.cell_x).max().unwrap();
+        assert_eq!(cells_in(&cells, x..=x, -100..=100).len(), cells.iter().filter(|cell| cell.cell_x == x).count());
+    }
+
+    #[test]
+    fn packs_without_an_encoding_take_the_one_of_their_version() {
+        let scratch = Scratch::new("no-encoding");
+        PackWriter::new(&scratch.0, 4, WINDOWS_1251, 1 << 20).finish().unwrap();
+        let mut bytes = fs::read(&scratch.0).unwrap();
+        let label = bytes[11] as usize;
+        bytes.drain(HEADER_LEN as usize..HEADER_LEN as usize + label);
+        bytes[11] = 0;
+        LE::write_u64(&mut bytes[16..24], HEADER_LEN);
+        fs::write(&scratch.0, &bytes).unwrap();
+        assert_eq!(Pack::open(&scratch.0).unwrap().encoding().name(), "windows-1252");
+    }
+
+    #[test]
+    fn unknown_propdump_versions_are_refused() {
+        let scratch = Scratch::new("unknown-version");
+        PackWriter::new(&scratch.0, 5, WINDOWS_1252, 1 << 20).finish().unwrap();
+        let mut bytes = fs::read(&scratch.0).unwrap();
+        bytes[10] = 9;
+        fs::write(&scratch.0, &bytes).unwrap();
+        assert!(Pack::open(&scratch.0).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+//! Reads propdumps on several threads.
+//!
+//! A reader thread splits the propdumps into records, in batches, using only the lengths in each record to find
+//! where it ends. A pool of workers reads and checks the fields of the records in a batch, decodes them and applies
+//! the selection, including looking for teleports to append. The calling thread gets the selected objects back in
+//! propdump order, up to the first damaged record.
+//!
+//! When recovering from damage the reader thread reads and checks every field itself, as that is how it tells where
+//! damaged records end. The workers then only decode, so recovering gains less from more threads.
+
+use failure;
+
+use std::collections::BTreeMap;
+use std::io::BufRead;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use aw::Object;
+use encoding::types::EncodingRef;
+
+use propdump::{Propdump, RecordBytes};
+use teleports::TeleportAppender;
+use RUNNING;
+
+/// Records handed to a worker at a time
+const BATCH: usize = 1024;
+
+/// A propdump to read, with the name to use for it in messages
+pub struct Input {
+    pub name: String,
+    pub propdump: Propdump<Box<dyn BufRead + Send>>
+}
+
+struct Batch {
+    seq: u64,
+    /// Name of the propdump, for messages
+    name: Arc<str>,
+    encoding: EncodingRef,
+    records: Result<Vec<RecordBytes>, failure::Error>
+}
+
+/// An object that passed the selection, with the teleport lines found in it
+struct Selected {
+    object: Object,
+    teleports: Vec<String>
+}
+
+/// The objects selected from a batch, along with the error that ended it early, if any
+type Decoded = (u64, Vec<Selected>, Option<failure::Error>);
+
+/// Splits every input into batches of records, returning how many damaged records were skipped.
+/// Stops at the first error, or when nobody is listening anymore.
+fn read(inputs: Vec<Input>, batches: SyncSender<Batch>) -> u64 {
+    let mut seq = 0;
+    let mut lost = 0;
+    for Input { name, mut propdump } in inputs {
+        let name: Arc<str> = Arc::from(name);
+        let encoding = propdump.encoding();
+        let mut records = Vec::with_capacity(BATCH);
+        loop {
+            let (error, end) = match propdump.next_bytes() {
+                Some(Ok(record)) => {
+                    records.push(record);
+                    (None, false)
+                },
+                Some(Err(err)) => (Some(format_err!("{}: {}", name, err)), true),
+                None => (None, true)
+            };
+            if records.len() == BATCH || (end && !records.is_empty()) {
+                let records = Ok(::std::mem::replace(&mut records, Vec::with_capacity(BATCH)));
+                if batches.send(Batch { seq, name: Arc::clone(&name), encoding, records }).is_err() {
+                    return lost;
+                }
+                seq += 1;
+            }
+            if let Some(err) = error {
+                let _ = batches.send(Batch { seq, name: Arc::clone(&name), encoding, records: Err(err) });
+                return lost;
+            }
+            if end {
+                break;
+            }
+        }
+        lost += propdump.lost_records();
+    }
+    lost
+}
+
+/// Hands the selected objects to `f` in batch order, appending found teleports along the way
+fn write<F>(decoded: Receiver<Decoded>, mut appender: Option<&mut TeleportAppender>, mut f: F) -> Result<(), failure::Error>
+    where F: FnMut(&Object) -> Result<(), failure::Error> {
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
+    for (seq, selected, error) in decoded {
+        pending.insert(seq, (selected, error));
+        while let Some((selected, error)) = pending.remove(&next) {
+            next += 1;
+            for Selected { object, teleports } in selected {
+                if !RUNNING.load(Ordering::SeqCst) {
+                    eprintln!("Quitting due to Ctrl-C");
+                    return Ok(());
+                }
+                if let Some(ref mut appender) = appender {
+                    appender.append(&teleports).expect("Unable to append to teleport append");
+                }
+                f(&object)?;
+            }
+            if let Some(err) = error {
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Hands every object of the inputs that `select` keeps to `f`, in order, until done or Ctrl-C is pressed.
+/// Returns how many damaged records were skipped.
+pub fn run<S, F>(inputs: Vec<Input>, threads: usize, select: S, appender: Option<&mut TeleportAppender>, f: F) -> Result<u64, failure::Error>
+    where S: Fn(&Object) -> bool + Sync,
+          F: FnMut(&Object) -> Result<(), failure::Error> {
+    let scanner = appender.as_ref().map(|appender| appender.scanner());
+    let (batch_sender, batches) = mpsc::sync_channel(threads * 2);
+    let batches = Arc::new(Mutex::new(batches));
+    let (decoded_sender, decoded) = mpsc::sync_channel(threads * 2);
+    thread::scope(|scope| {
+        let reader = scope.spawn(move || read(inputs, batch_sender));
+        for _ in 0..threads {
+            let batches = Arc::clone(&batches);
+            let decoded_sender: SyncSender<Decoded> = decoded_sender.clone();
+            let (select, scanner) = (&select, scanner.clone());
+            scope.spawn(move || loop {
+                let Batch { seq, name, encoding, records } = match batches.lock().unwrap().recv() {
+                    Ok(batch) => batch,
+                    Err(_) => return
+                };
+                let mut selected = Vec::new();
+                let mut error = None;
+                match records {
+                    Ok(records) => for record in records {
+                        let object = match record.parse() {
+                            Ok(record) => record.decode(encoding),
+                            Err(err) => {
+                                error = Some(format_err!("{}: {}", name, err));
+                                break;
+                            }
+                        };
+                        if select(&object) {
+                            selected.push(Selected {
+                                teleports: scanner.as_ref().map(|scanner| scanner.scan(&object)).unwrap_or_default(),
+                                object
+                            });
+                        }
+                    },
+                    Err(err) => error = Some(err)
+                }
+                if decoded_sender.send((seq, selected, error)).is_err() {
+                    return;
+                }
+            });
+        }
+        // The workers hold the only other handles, so the channels close once they are done
+        drop(batches);
+        drop(decoded_sender);
+        write(decoded, appender, f)?;
+        Ok(reader.join().expect("Propdump reader panicked"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// A version 3 propdump whose records have their index as citnum, with a bad y at `bad`
+    fn propdump(records: usize, bad: usize) -> Vec<u8> {
+        let mut dump = b"propdump version 3\r\n".to_vec();
+        for i in 0..records {
+            let y = if i == bad { "bad" } else { "0" };
+            dump.extend(format!("{} 0 0 {} 0 0 0 0 3 0 0 a.r\r\n", i, y).into_bytes());
+        }
+        dump
+    }
+
+    /// The citnums of the objects read, and the error that stopped the reading, if any
+    fn citnums(dump: &[u8], threads: usize) -> (Vec<i32>, Option<String>) {
+        let file: Box<dyn BufRead + Send> = Box::new(Cursor::new(dump.to_vec()));
+        let input = Input { name: "test".to_string(), propdump: Propdump::new(file).unwrap() };
+        let mut citnums = Vec::new();
+        let result = run(vec![input], threads, |_| true, None, |object| {
+            citnums.push(object.citnum);
+            Ok(())
+        });
+        (citnums, result.err().map(|err| err.to_string()))
+    }
+
+    #[test]
+    fn objects_come_back_in_order_whatever_the_thread_count() {
+        let dump = propdump(BATCH * 5 + 7, usize::MAX);
+        let expected: Vec<i32> = (0..(BATCH * 5 + 7) as i32).collect();
+        for threads in &[1, 2, 4, 8] {
+            assert_eq!(citnums(&dump, *threads), (expected.clone(), None));
+        }
+    }
+
+    #[test]
+    fn a_damaged_record_in_a_middle_batch_ends_the_output_there() {
+        let bad = BATCH * 2 + 10;
+        let dump = propdump(BATCH * 5, bad);
+        for threads in &[1, 2, 4, 8] {
+            let (citnums, err) = citnums(&dump, *threads);
+            assert_eq!(citnums, (0..bad as i32).collect::<Vec<_>>());
+            let err = err.expect("The damaged record was not reported");
+            assert!(err.starts_with(&format!("test: Propdump record {} ", bad + 1)), "{}", err);
+        }
+    }
+}
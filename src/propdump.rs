@@ -1,36 +1,186 @@
 use failure;
 
-use std::io::{self, Read, BufRead};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::mem;
 use std::error;
+use std::fmt;
 use std::str::FromStr;
 
-use encoding::{Encoding, DecoderTrap};
+use encoding::DecoderTrap;
 use encoding::types::EncodingRef;
 use encoding::all::{UTF_8, WINDOWS_1252};
 
-use aw::Object;
+use aw::{self, Object};
+use codepage::{self, CodePage};
 
-trait BufReadHelpers: BufRead {
-    fn read_until_exclusive(&mut self, byte: u8, buf: &mut Vec<u8>) -> io::Result<usize>;
-    fn read_item<N>(&mut self) -> Result<N, failure::Error>
-        where N: FromStr,
-              N::Err: error::Error + Send + Sync + 'static;
+/// Why a field of a propdump record could not be read
+#[derive(Debug)]
+pub enum ParseErrorCause {
+    Io(io::Error),
+    /// The propdump ended in the middle of the record
+    Eof,
+    BadNumber(String),
+    /// An x or z coordinate outside the cells AW can hold
+    OutOfRange(i32),
+    BadHex,
+    /// The line went on after the record ended
+    Trailing,
+    /// While recovering, the text and data of a record would take more bytes than are read ahead
+    TooLong(u64)
 }
 
-impl<R: BufRead> BufReadHelpers for R {
-    fn read_until_exclusive(&mut self, byte: u8, buf: &mut Vec<u8>) -> io::Result<usize> {
-        let result = self.read_until(byte, buf);
-        buf.pop();
-        result
+/// Where and why reading a propdump record failed
+#[derive(Debug)]
+pub struct ParseError {
+    /// Number of the record, counting from 1
+    pub record: u64,
+    /// Offset in the propdump of the start of the field, counting the header line
+    pub offset: u64,
+    pub field: &'static str,
+    pub cause: ParseErrorCause
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Propdump record {} at byte {}: bad {}: ", self.record, self.offset, self.field)?;
+        match self.cause {
+            ParseErrorCause::Io(ref err) => write!(f, "{}", err),
+            ParseErrorCause::Eof => write!(f, "unexpected end of file"),
+            ParseErrorCause::BadNumber(ref text) => write!(f, "{:?} is not a number", text),
+            ParseErrorCause::OutOfRange(coord) => write!(f, "{} is beyond the last cell, {} to {} are allowed", coord, aw::MIN_COORD, aw::MAX_COORD),
+            ParseErrorCause::BadHex => write!(f, "invalid hex digits"),
+            ParseErrorCause::Trailing => write!(f, "unexpected bytes after the record"),
+            ParseErrorCause::TooLong(len) => write!(f, "{} bytes of text and data is more than the {} read ahead while recovering", len, MAX_RECOVER_LEN)
+        }
+    }
+}
+
+impl error::Error for ParseError {
+    fn description(&self) -> &str {
+        "Propdump parse error"
+    }
+}
+
+/// Longest text accepted as a number field
+const MAX_NUMBER_LEN: usize = 20;
+
+/// Most bytes of text and data a record is taken to have while recovering, which limits how far ahead each try at
+/// reading a record looks
+const MAX_RECOVER_LEN: usize = 1 << 20;
+
+/// Lines at the start of a propdump that its encoding is guessed from, and the most bytes read for that
+const SAMPLE_LINES: usize = 1000;
+const SAMPLE_BYTES: usize = 1 << 20;
+
+/// A reader that can go back to read again what it read since a mark
+struct Replay<R: BufRead> {
+    inner: R,
+    /// Bytes to read again, and those read from `inner` since the mark
+    buffer: Vec<u8>,
+    pos: usize,
+    /// Where the bytes consumed since `mark` was called start in `buffer`
+    mark: Option<usize>
+}
+
+impl<R: BufRead> Replay<R> {
+    fn new(inner: R) -> Self {
+        Replay {
+            inner,
+            buffer: Vec::new(),
+            pos: 0,
+            mark: None
+        }
+    }
+
+    /// Keeps the bytes consumed from now on, to be taken or read again
+    fn mark(&mut self) {
+        // Bytes before the position are not needed any more. Dropping them only once they are half the buffer
+        // keeps going back over the same bytes linear.
+        if self.pos > self.buffer.len() / 2 {
+            self.buffer.drain(..self.pos);
+            self.pos = 0;
+        }
+        self.mark = Some(self.pos);
+    }
+
+    /// The bytes consumed since `mark`, which stops keeping them
+    fn take_marked(&mut self) -> Vec<u8> {
+        let mark = match self.mark.take() {
+            Some(mark) => mark,
+            None => return Vec::new()
+        };
+        if mark == 0 && self.pos == self.buffer.len() {
+            self.pos = 0;
+            return mem::take(&mut self.buffer);
+        }
+        let bytes = self.buffer[mark..self.pos].to_vec();
+        self.release();
+        bytes
+    }
+
+    /// Goes back to `skip` bytes after the mark, to read what follows again, and stops keeping bytes.
+    /// At least `skip` bytes must have been consumed since `mark`.
+    fn rewind(&mut self, skip: usize) {
+        if let Some(mark) = self.mark.take() {
+            self.pos = mark + skip;
+            self.release();
+        }
+    }
+
+    /// Empties the buffer once everything in it has been read, unless it is being kept
+    fn release(&mut self) {
+        if self.mark.is_none() && self.pos == self.buffer.len() {
+            self.buffer.clear();
+            self.pos = 0;
+        }
+    }
+}
+
+impl<R: BufRead + Seek> Replay<R> {
+    fn seek(&mut self, offset: u64) -> io::Result<()> {
+        self.buffer.clear();
+        self.pos = 0;
+        self.mark = None;
+        self.inner.seek(SeekFrom::Start(offset))?;
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for Replay<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = {
+            let available = self.fill_buf()?;
+            let read = available.len().min(buf.len());
+            buf[..read].copy_from_slice(&available[..read]);
+            read
+        };
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl<R: BufRead> BufRead for Replay<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos < self.buffer.len() {
+            Ok(&self.buffer[self.pos..])
+        } else {
+            self.inner.fill_buf()
+        }
     }
-    
-    fn read_item<N>(&mut self) -> Result<N, failure::Error>
-        where N: FromStr,
-              N::Err: error::Error + Send + Sync + 'static {
-        let mut buf: Vec<u8> = Vec::new();
-        self.read_until_exclusive(b' ', &mut buf)?;
-        let s = ::std::str::from_utf8(&buf)?;
-        Ok(N::from_str(s)?)
+
+    fn consume(&mut self, amt: usize) {
+        if self.pos < self.buffer.len() {
+            self.pos += amt;
+        } else {
+            if self.mark.is_some() {
+                // The bytes being consumed are still buffered
+                let available = self.inner.fill_buf().unwrap_or(&[]);
+                self.buffer.extend_from_slice(&available[..amt.min(available.len())]);
+                self.pos = self.buffer.len();
+            }
+            self.inner.consume(amt);
+        }
+        self.release();
     }
 }
 
@@ -45,81 +195,914 @@ fn restore_newlines(buffer: &mut [u8]) {
             }
         }
     }
-    if buffer.len() > 0 && buffer[0] == b'\x7F' {
+    if !buffer.is_empty() && buffer[0] == b'\x7F' {
         buffer[0] = b'\n';
     }
 }
 
-pub struct Propdump<R: BufRead> {
+/// Inverse of `restore_newlines`
+fn escape_newlines(buffer: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(buffer.len());
+    let mut i = 0;
+    while i < buffer.len() {
+        if buffer[i] == b'\r' && buffer.get(i+1) == Some(&b'\n') {
+            escaped.extend_from_slice(b"\x80\x7F");
+            i += 2;
+        } else {
+            escaped.push(if buffer[i] == b'\n' { b'\x7F' } else { buffer[i] });
+            i += 1;
+        }
+    }
+    escaped
+}
+
+/// Reads the first lines of a propdump to guess its encoding from, leaving them to be read again
+fn sample<R: BufRead>(file: &mut Replay<R>) -> io::Result<Vec<u8>> {
+    let mut sample = Vec::new();
+    file.mark();
+    for _ in 0..SAMPLE_LINES {
+        // Lines are cut short at the limit too, in case there are no line breaks
+        let left = (SAMPLE_BYTES - sample.len()) as u64;
+        if left == 0 || (&mut *file).take(left).read_until(b'\n', &mut sample)? == 0 {
+            break;
+        }
+    }
+    file.rewind(0);
+    Ok(sample)
+}
+
+/// Text with the line breaks of descriptions and actions back in place, as the escapes are not valid in every
+/// encoding
+fn escape_free(text: &[u8]) -> Vec<u8> {
+    let mut text = text.to_vec();
+    restore_newlines(&mut text);
+    text
+}
+
+/// The record layout and text encoding of a propdump version
+#[derive(Copy, Clone)]
+pub struct Schema {
+    pub version: u8,
+    /// Whether records have the object type after the rotation
+    pub type_: bool,
+    /// Whether records have the length of the object data after the action length, and the data itself,
+    /// hex encoded, after the action
+    pub data: bool,
+    pub encoding: EncodingRef
+}
+
+static SCHEMAS: [Schema; 3] = [
+    Schema { version: 3, type_: false, data: false, encoding: WINDOWS_1252 },
+    Schema { version: 4, type_: true, data: true, encoding: WINDOWS_1252 },
+    Schema { version: 5, type_: true, data: true, encoding: UTF_8 }
+];
+
+impl Schema {
+    pub fn of(version: u8) -> Option<&'static Schema> {
+        SCHEMAS.iter().find(|schema| schema.version == version)
+    }
+}
+
+/// Writes objects as a propdump, in the layout `Propdump` reads
+pub struct PropdumpWriter<W: Write> {
+    file: W,
+    schema: &'static Schema,
+    code_page: CodePage,
+    /// Objects whose type or data the version cannot hold
+    stripped: u64,
+    /// Objects with characters the encoding lacks, written as '?'
+    lossy: u64
+}
+
+impl<W: Write> PropdumpWriter<W> {
+    /// Writes the header line for `version`, which must be 3, 4 or 5
+    pub fn new(mut file: W, version: u8) -> Result<Self, failure::Error> {
+        let schema = Schema::of(version).ok_or_else(|| format_err!("Unsupported propdump version {}", version))?;
+        write!(file, "propdump version {}\r\n", version)?;
+        Ok(PropdumpWriter {
+            file,
+            schema,
+            code_page: CodePage::plain(schema.encoding),
+            stripped: 0,
+            lossy: 0
+        })
+    }
+
+    /// Writes the text in another encoding than the one of the version, such as the one a propdump was read in
+    pub fn set_encoding(&mut self, encoding: EncodingRef) {
+        self.code_page = CodePage::plain(encoding);
+    }
+
+    /// Writes an object, counting what it loses. Objects keep their type and data only in versions 4 and 5.
+    pub fn write(&mut self, object: &Object) -> Result<(), failure::Error> {
+        let (name, lost_name) = self.code_page.encode(&object.name);
+        let (desc, lost_desc) = self.code_page.encode(&object.desc);
+        let (action, lost_action) = self.code_page.encode(&object.action);
+        let (desc, action) = (escape_newlines(&desc), escape_newlines(&action));
+        if lost_name + lost_desc + lost_action > 0 {
+            self.lossy += 1;
+        }
+        if !self.schema.type_ && object.type_ != 0 || !self.schema.data && !object.data.is_empty() {
+            self.stripped += 1;
+        }
+        let w = &mut self.file;
+        write!(w, "{} {} {} {} {} {} {} {} ", object.citnum, object.time, object.x, object.y, object.z, object.yaw, object.tilt, object.roll)?;
+        if self.schema.type_ {
+            write!(w, "{} ", object.type_)?;
+        }
+        write!(w, "{} {} {} ", name.len(), desc.len(), action.len())?;
+        if self.schema.data {
+            write!(w, "{} ", object.data.len())?;
+        }
+        w.write_all(&name)?;
+        w.write_all(&desc)?;
+        w.write_all(&action)?;
+        if self.schema.data {
+            for byte in &object.data {
+                write!(w, "{:02X}", byte)?;
+            }
+        }
+        w.write_all(b"\r\n")?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), failure::Error> {
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Reports the objects that lost something, to standard error
+    pub fn report(&self) {
+        if self.stripped > 0 {
+            eprintln!("{} objects lost their type or data, which version {} propdumps cannot hold", self.stripped, self.schema.version);
+        }
+        if self.lossy > 0 {
+            eprintln!("{} objects had characters {} lacks written as '?'", self.lossy, self.code_page.name());
+        }
+    }
+}
+
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10
+    }
+}
+
+/// A record that has been checked but whose text and data are still as found in the propdump
+pub struct RawRecord {
+    citnum: i32,
+    time: i32,
+    x: i32,
+    y: i32,
+    z: i32,
+    yaw: i16,
+    tilt: i16,
+    roll: i16,
+    type_: i32,
+    name: Vec<u8>,
+    desc: Vec<u8>,
+    action: Vec<u8>,
+    /// Checked to be hex digits
+    hexdata: Vec<u8>
+}
+
+impl RawRecord {
+    /// The cell the object is in
+    pub fn cell(&self) -> (i16, i16) {
+        aw::cell(self.x, self.z)
+    }
+
+    /// Decodes the record, with the encoding of the propdump it came from
+    pub fn decode(mut self, encoding: EncodingRef) -> Object {
+        restore_newlines(&mut self.desc);
+        restore_newlines(&mut self.action);
+        let decode = |text: &[u8]| encoding.decode(text, DecoderTrap::Replace).expect("Replacing decoder failed");
+        Object {
+            type_: self.type_,
+            citnum: self.citnum,
+            time: self.time,
+            x: self.x,
+            y: self.y,
+            z: self.z,
+            yaw: self.yaw,
+            tilt: self.tilt,
+            roll: self.roll,
+            name: decode(&self.name),
+            desc: decode(&self.desc),
+            action: decode(&self.action),
+            data: self.hexdata.chunks(2).map(|digits| hex_value(digits[0]) << 4 | hex_value(digits[1])).collect(),
+            ..Object::default()
+        }
+    }
+}
+
+/// The bytes of a record, found using only its lengths, for its fields to be read and checked elsewhere
+pub struct RecordBytes {
+    schema: &'static Schema,
+    /// Number of the record, counting from 1
+    record: u64,
+    /// Offset of the record in the propdump
+    offset: u64,
+    bytes: Vec<u8>
+}
+
+impl RecordBytes {
+    /// Reads and checks the fields of the record
+    pub fn parse(&self) -> Result<RawRecord, ParseError> {
+        let mut fields = FieldReader {
+            file: &self.bytes[..],
+            offset: self.offset,
+            record: self.record,
+            max_len: usize::MAX
+        };
+        fields.read_record(self.schema)
+    }
+}
+
+/// Reads the fields of records, from a propdump or from the bytes of a record, keeping track of where it is
+struct FieldReader<R: BufRead> {
     file: R,
-    v4: bool,
+    /// Bytes read so far, counting the header line
+    offset: u64,
+    /// Number of the record being read
+    record: u64,
+    /// Most bytes of text and data a record may have
+    max_len: usize
+}
+
+pub struct Propdump<R: BufRead> {
+    fields: FieldReader<Replay<R>>,
+    schema: &'static Schema,
+    /// Encoding of the text, the one of the version unless a version 3 or 4 propdump looks otherwise
     encoding: EncodingRef,
+    /// Offset of the start of the last record
+    start: u64,
+    done: bool,
+    recover: bool,
+    /// Where the damaged records being skipped start, along with what was wrong with the first of them and
+    /// how many record headers were found among them
+    skipping: Option<(u64, ParseError, u64)>,
+    lost: u64
 }
 
 impl<R: BufRead> Propdump<R> {
     pub fn new(mut file: R) -> Result<Self, failure::Error> {
         let mut first_line = String::new();
         file.read_line(&mut first_line)?;
-        if first_line != "propdump version 3\r\n" && first_line != "propdump version 4\r\n" && first_line != "propdump version 5\r\n" {
-            bail!("Unrecognized first line of propdump!");
-        }
-        let v4 = first_line == "propdump version 4\r\n";
-        let encoding = if first_line == "propdump version 5\r\n" {
-            UTF_8 as EncodingRef
+        let words: Vec<&str> = first_line.split_whitespace().collect();
+        let schema = match words[..] {
+            ["propdump", "version", version] => version.parse().ok().and_then(Schema::of),
+            _ => None
+        };
+        let schema = match schema {
+            Some(schema) => schema,
+            None => bail!("Unrecognized first line of propdump!")
+        };
+        let mut file = Replay::new(file);
+        // Version 5 is UTF-8 by definition, older versions are in whatever code page the server used
+        let encoding = if schema.version < 5 {
+            let sample = sample(&mut file)?;
+            codepage::detect(&escape_free(&sample)).unwrap_or(schema.encoding)
         } else {
-            WINDOWS_1252 as EncodingRef
+            schema.encoding
         };
         Ok(Propdump {
-            v4: v4,
-            file: file,
-            encoding: encoding
+            fields: FieldReader {
+                file,
+                offset: first_line.len() as u64,
+                record: 0,
+                max_len: usize::MAX
+            },
+            schema,
+            encoding,
+            start: 0,
+            done: false,
+            recover: false,
+            skipping: None,
+            lost: 0
         })
     }
+
+    pub fn version(&self) -> u8 {
+        self.schema.version
+    }
+
+    pub fn schema(&self) -> &'static Schema {
+        self.schema
+    }
+
+    /// Encoding the text is decoded with
+    pub fn encoding(&self) -> EncodingRef {
+        self.encoding
+    }
+
+    /// Decodes the text with the given encoding instead of the detected one
+    pub fn set_encoding(&mut self, encoding: EncodingRef) {
+        self.encoding = encoding;
+    }
+
+    /// Number and offset of the last record read
+    pub fn position(&self) -> (u64, u64) {
+        (self.fields.record, self.start)
+    }
+
+    /// Instead of stopping at a damaged record, skip ahead to the next place a whole record can be read from
+    pub fn set_recover(&mut self, recover: bool) {
+        self.recover = recover;
+        self.fields.max_len = if recover { MAX_RECOVER_LEN } else { usize::MAX };
+    }
+
+    /// Damaged records skipped so far: the record headers found in the skipped bytes, and at least one for
+    /// each stretch of them
+    pub fn lost_records(&self) -> u64 {
+        self.lost
+    }
 }
 
-impl<R: BufRead> Iterator for Propdump<R> {
-    type Item = Object;
-    
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut object = Object::default();
-        let maybe_citnum = self.file.read_item();
-        if let Err(err) = maybe_citnum {
-            return None;
+impl<R: BufRead> FieldReader<R> {
+    fn error(&self, field: &'static str, offset: u64, cause: ParseErrorCause) -> ParseError {
+        ParseError {
+            record: self.record,
+            offset,
+            field,
+            cause
         }
-        object.citnum = maybe_citnum.expect("Fatal error reading propdump");
-        object.time = self.file.read_item().expect("Fatal error reading propdump");
-        object.x = self.file.read_item().expect("Fatal error reading propdump");
-        object.y = self.file.read_item().expect("Fatal error reading propdump");
-        object.z = self.file.read_item().expect("Fatal error reading propdump");
-        object.yaw = self.file.read_item().expect("Fatal error reading propdump");
-        object.tilt = self.file.read_item().expect("Fatal error reading propdump");
-        object.roll = self.file.read_item().expect("Fatal error reading propdump");
-        if self.v4 {
-            object.type_ = self.file.read_item().expect("Fatal error reading propdump");
-        }
-        let namelen: usize = self.file.read_item().expect("Fatal error reading propdump");
-        let desclen: usize = self.file.read_item().expect("Fatal error reading propdump");
-        let actionlen: usize = self.file.read_item().expect("Fatal error reading propdump");
-        let datalen: usize = if self.v4 {
-            self.file.read_item().expect("Fatal error reading propdump")
+    }
+
+    /// Reads `len` bytes, or fewer at the end of the file
+    fn read_up_to(&mut self, field: &'static str, len: usize) -> Result<Vec<u8>, ParseError> {
+        let start = self.offset;
+        let mut buf = Vec::new();
+        let read = (&mut self.file).take(len as u64).read_to_end(&mut buf)
+            .map_err(|err| self.error(field, start, ParseErrorCause::Io(err)))?;
+        self.offset += read as u64;
+        Ok(buf)
+    }
+
+    fn read_bytes(&mut self, field: &'static str, len: usize) -> Result<Vec<u8>, ParseError> {
+        let start = self.offset;
+        let buf = self.read_up_to(field, len)?;
+        if buf.len() < len {
+            return Err(self.error(field, start, ParseErrorCause::Eof));
+        }
+        Ok(buf)
+    }
+
+    /// Skips over any of `bytes`
+    fn skip(&mut self, field: &'static str, bytes: &[u8]) -> Result<(), ParseError> {
+        loop {
+            let (skipped, more) = match self.file.fill_buf() {
+                Ok(available) => {
+                    let skipped = available.iter().take_while(|byte| bytes.contains(byte)).count();
+                    (skipped, skipped > 0 && skipped == available.len())
+                },
+                Err(err) => return Err(self.error(field, self.offset, ParseErrorCause::Io(err)))
+            };
+            self.file.consume(skipped);
+            self.offset += skipped as u64;
+            if !more {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Reads a number followed by a space or tab, skipping any spaces and tabs before it
+    fn read_item<N: FromStr>(&mut self, field: &'static str) -> Result<N, ParseError> {
+        self.skip(field, b" \t")?;
+        let start = self.offset;
+        let mut buf = Vec::new();
+        loop {
+            let (used, found) = match self.file.fill_buf() {
+                Ok([]) => return Err(self.error(field, start, ParseErrorCause::Eof)),
+                Ok(available) => {
+                    // Enough to tell the number is too long, without reading on through whatever follows
+                    let available = &available[..available.len().min(MAX_NUMBER_LEN + 1 - buf.len())];
+                    match available.iter().position(|&byte| byte == b' ' || byte == b'\t') {
+                        Some(end) => {
+                            buf.extend_from_slice(&available[..end]);
+                            (end + 1, true)
+                        },
+                        None => {
+                            buf.extend_from_slice(available);
+                            (available.len(), false)
+                        }
+                    }
+                },
+                Err(err) => return Err(self.error(field, start, ParseErrorCause::Io(err)))
+            };
+            self.file.consume(used);
+            self.offset += used as u64;
+            if found || buf.len() > MAX_NUMBER_LEN {
+                break;
+            }
+        }
+        let text = String::from_utf8_lossy(&buf);
+        N::from_str(&text).map_err(|_| self.error(field, start, ParseErrorCause::BadNumber(text.into_owned())))
+    }
+
+    /// Reads an x or z coordinate, checking that its cell fits in an i16
+    fn read_coordinate(&mut self, field: &'static str) -> Result<i32, ParseError> {
+        self.skip(field, b" \t")?;
+        let start = self.offset;
+        let coord = self.read_item(field)?;
+        if !(aw::MIN_COORD..=aw::MAX_COORD).contains(&coord) {
+            return Err(self.error(field, start, ParseErrorCause::OutOfRange(coord)));
+        }
+        Ok(coord)
+    }
+
+    /// Reads the end of a record: spaces or tabs, then LF, CRLF or the end of the file
+    fn read_end_of_line(&mut self) -> Result<(), ParseError> {
+        self.skip("end of line", b" \t")?;
+        let start = self.offset;
+        let end = self.read_up_to("end of line", 1)?;
+        match end[..] {
+            [] | [b'\n'] => Ok(()),
+            [b'\r'] if self.read_up_to("end of line", 1)? == b"\n" => Ok(()),
+            _ => Err(self.error("end of line", start, ParseErrorCause::Trailing))
+        }
+    }
+
+    /// Skips a number followed by a space or tab without reading it, along with any spaces and tabs before it
+    fn skip_item(&mut self, field: &'static str) -> Result<(), ParseError> {
+        self.skip(field, b" \t")?;
+        let start = self.offset;
+        loop {
+            let (used, found) = match self.file.fill_buf() {
+                Ok([]) => return Err(self.error(field, start, ParseErrorCause::Eof)),
+                Ok(available) => match available.iter().position(|&byte| byte == b' ' || byte == b'\t') {
+                    Some(end) => (end + 1, true),
+                    None => (available.len(), false)
+                },
+                Err(err) => return Err(self.error(field, start, ParseErrorCause::Io(err)))
+            };
+            self.file.consume(used);
+            self.offset += used as u64;
+            if found || self.offset - start > MAX_NUMBER_LEN as u64 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Skips `len` bytes without keeping them
+    fn skip_bytes(&mut self, field: &'static str, len: usize) -> Result<(), ParseError> {
+        let start = self.offset;
+        let skipped = io::copy(&mut (&mut self.file).take(len as u64), &mut io::sink())
+            .map_err(|err| self.error(field, start, ParseErrorCause::Io(err)))?;
+        self.offset += skipped;
+        if skipped < len as u64 {
+            return Err(self.error(field, start, ParseErrorCause::Eof));
+        }
+        Ok(())
+    }
+
+    /// Reads the lengths of a record, checking they add up to no more than `max_len`
+    fn read_lengths(&mut self, schema: &Schema) -> Result<[usize; 4], ParseError> {
+        let start = self.offset;
+        let namelen: usize = self.read_item("name length")?;
+        let desclen: usize = self.read_item("description length")?;
+        let actionlen: usize = self.read_item("action length")?;
+        let datalen: usize = if schema.data {
+            self.read_item("data length")?
         } else {
             0
         };
-        let mut name = vec![0; namelen];
-        let mut desc = vec![0; desclen];
-        let mut action = vec![0; actionlen];
-        let mut hexdata = vec![0; datalen * 2];
-        self.file.read_exact(&mut name).expect("Fatal error reading propdump");
-        object.name = self.encoding.decode(&name, DecoderTrap::Replace).expect("Fatal decode error reading propdump");
-        self.file.read_exact(&mut desc).expect("Fatal error reading propdump");
-        restore_newlines(&mut desc);
-        object.desc = self.encoding.decode(&desc, DecoderTrap::Replace).expect("Fatal decode error reading propdump");
-        self.file.read_exact(&mut action).expect("Fatal error reading propdump");
-        restore_newlines(&mut action);
-        object.action = self.encoding.decode(&action, DecoderTrap::Replace).expect("Fatal decode error reading propdump");
-        self.file.read_exact(&mut hexdata).expect("Fatal error reading propdump");
-        object.data = hexdata.chunks(2).map(String::from_utf8_lossy).map(|digits| u8::from_str_radix(&digits, 16).expect("Unable to parse hex digits in data")).collect();
-        let _nl = self.file.read_exact(&mut [0u8; 2]); // Read past newline
-        Some(object)
-    }
-}
\ No newline at end of file
+        let len = namelen as u64 + desclen as u64 + actionlen as u64 + datalen as u64 * 2;
+        if len > self.max_len as u64 {
+            return Err(self.error("lengths", start, ParseErrorCause::TooLong(len)));
+        }
+        Ok([namelen, desclen, actionlen, datalen])
+    }
+
+    /// Reads past a record using only its lengths. The other numbers are left for `read_record` to check.
+    fn skip_record(&mut self, schema: &Schema) -> Result<(), ParseError> {
+        let numbers = ["citnum", "time", "x", "y", "z", "yaw", "tilt", "roll", "type"];
+        for field in &numbers[..if schema.type_ { 9 } else { 8 }] {
+            self.skip_item(field)?;
+        }
+        let [namelen, desclen, actionlen, datalen] = self.read_lengths(schema)?;
+        self.skip_bytes("name", namelen)?;
+        self.skip_bytes("description", desclen)?;
+        self.skip_bytes("action", actionlen)?;
+        self.skip_bytes("data", datalen * 2)?;
+        self.read_end_of_line()
+    }
+
+    fn read_record(&mut self, schema: &Schema) -> Result<RawRecord, ParseError> {
+        let citnum = self.read_item("citnum")?;
+        let time = self.read_item("time")?;
+        let x = self.read_coordinate("x")?;
+        let y = self.read_item("y")?;
+        let z = self.read_coordinate("z")?;
+        let yaw = self.read_item("yaw")?;
+        let tilt = self.read_item("tilt")?;
+        let roll = self.read_item("roll")?;
+        let type_ = if schema.type_ {
+            self.read_item("type")?
+        } else {
+            0
+        };
+        let [namelen, desclen, actionlen, datalen] = self.read_lengths(schema)?;
+        let name = self.read_bytes("name", namelen)?;
+        let desc = self.read_bytes("description", desclen)?;
+        let action = self.read_bytes("action", actionlen)?;
+        let start = self.offset;
+        let hexdata = self.read_bytes("data", datalen * 2)?;
+        if !hexdata.iter().all(u8::is_ascii_hexdigit) {
+            return Err(self.error("data", start, ParseErrorCause::BadHex));
+        }
+        self.read_end_of_line()?;
+        Ok(RawRecord {
+            citnum,
+            time,
+            x,
+            y,
+            z,
+            yaw,
+            tilt,
+            roll,
+            type_,
+            name,
+            desc,
+            action,
+            hexdata
+        })
+    }
+}
+
+impl<R: BufRead> Propdump<R> {
+    /// Reports the damaged records skipped, which end at `end`
+    fn end_skipping(&mut self, end: u64) {
+        if let Some((start, err, headers)) = self.skipping.take() {
+            // The records lost keep their numbers
+            self.fields.record += headers.max(1);
+            self.lost += headers.max(1);
+            eprintln!("Skipped damaged propdump bytes {} to {}. First problem: {}", start, end, err);
+        }
+    }
+
+    /// Tries reading a record at each byte in turn until one reads without problems. Records are read here to
+    /// tell whether they are damaged, and each try reads at most `MAX_RECOVER_LEN` bytes of text and data.
+    fn next_recovering(&mut self) -> Option<Result<RecordBytes, ParseError>> {
+        loop {
+            let before = self.fields.offset;
+            match self.next_start() {
+                Ok(true) => (),
+                Ok(false) => {
+                    let end = self.fields.offset;
+                    self.end_skipping(end);
+                    self.done = true;
+                    return None;
+                },
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+            // A try starting in the middle of a number is not counted as a record, even if it reads like one
+            let boundary = self.skipping.is_none() || self.fields.offset > before;
+            let start = self.start;
+            self.fields.file.mark();
+            let err = match self.fields.read_record(self.schema) {
+                Ok(_) => {
+                    let bytes = self.fields.file.take_marked();
+                    self.end_skipping(start);
+                    return Some(Ok(RecordBytes {
+                        schema: self.schema,
+                        record: self.fields.record,
+                        offset: start,
+                        bytes
+                    }));
+                },
+                Err(err @ ParseError { cause: ParseErrorCause::Io(_), .. }) => {
+                    self.done = true;
+                    return Some(Err(err));
+                },
+                Err(err) => err
+            };
+            // The numbers and lengths read, so this was a record, damaged further on
+            let header = boundary && ["name", "description", "action", "data", "end of line"].contains(&err.field);
+            let skipping = self.skipping.get_or_insert((start, err, 0));
+            if header {
+                skipping.2 += 1;
+            }
+            self.fields.file.rewind(1);
+            self.fields.offset = start + 1;
+            self.fields.record -= 1;
+        }
+    }
+
+    /// Moves on to the start of the next record, returning false at the end of the propdump
+    fn next_start(&mut self) -> Result<bool, ParseError> {
+        self.fields.record += 1;
+        self.fields.skip("citnum", b" \t\r\n")?;
+        match self.fields.file.fill_buf() {
+            Ok([]) => Ok(false),
+            Ok(_) => {
+                self.start = self.fields.offset;
+                Ok(true)
+            },
+            Err(err) => Err(self.fields.error("citnum", self.fields.offset, ParseErrorCause::Io(err)))
+        }
+    }
+
+    /// Finds the next record using only its lengths, leaving its fields to be read and checked elsewhere.
+    /// Ends cleanly only when the propdump ends between records. Stops after the first error.
+    pub fn next_bytes(&mut self) -> Option<Result<RecordBytes, ParseError>> {
+        if self.done {
+            return None;
+        }
+        if self.recover {
+            return self.next_recovering();
+        }
+        match self.next_start() {
+            Ok(true) => (),
+            Ok(false) => {
+                self.done = true;
+                return None;
+            },
+            Err(err) => {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+        self.fields.file.mark();
+        let result = self.fields.skip_record(self.schema);
+        let record = RecordBytes {
+            schema: self.schema,
+            record: self.fields.record,
+            offset: self.start,
+            bytes: self.fields.file.take_marked()
+        };
+        match result {
+            Ok(()) => Some(Ok(record)),
+            Err(err) => {
+                self.done = true;
+                // Report the first problem, which may be in one of the numbers skipped over
+                Some(Err(match record.parse() {
+                    Err(first) if first.offset < err.offset => first,
+                    _ => err
+                }))
+            }
+        }
+    }
+
+    /// Reads the next record without decoding it, so the decoding can happen elsewhere.
+    /// Ends cleanly only when the propdump ends between records. Stops after the first error.
+    pub fn next_raw(&mut self) -> Option<Result<RawRecord, ParseError>> {
+        if self.done {
+            return None;
+        }
+        if self.recover {
+            return self.next_recovering().map(|record| record.and_then(|record| record.parse()));
+        }
+        match self.next_start() {
+            Ok(true) => (),
+            Ok(false) => {
+                self.done = true;
+                return None;
+            },
+            Err(err) => {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+        let result = self.fields.read_record(self.schema);
+        self.done = result.is_err();
+        Some(result)
+    }
+}
+
+impl<R: BufRead + Seek> Propdump<R> {
+    /// Carries on reading from the start of a record found before, given its number and offset as from `position`
+    pub fn seek(&mut self, record: u64, offset: u64) -> io::Result<()> {
+        self.fields.file.seek(offset)?;
+        self.fields.record = record - 1;
+        self.fields.offset = offset;
+        self.done = false;
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Iterator for Propdump<R> {
+    type Item = Result<Object, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let encoding = self.encoding;
+        self.next_raw().map(|record| record.map(|record| record.decode(encoding)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V4: &[u8] = b"propdump version 4\r\n\
+        1 100 -1500 0 2500 0 0 0 2 8 11 0 2 tree.rwxhello\x7Fthere01FF\r\n\
+        \r\n\
+        2 200 10 20 30 1 2 3 1 7 0 16 0 sign.rwcreate color red  \n\
+        3 300 0 0 0 0 0 0 1 3 0 0 0 a.r";
+
+    fn objects_by_bytes(dump: &[u8]) -> Vec<Result<Object, String>> {
+        let mut propdump = Propdump::new(dump).unwrap();
+        let encoding = propdump.encoding();
+        let mut objects = Vec::new();
+        while let Some(record) = propdump.next_bytes() {
+            objects.push(record.and_then(|record| record.parse()).map(|record| record.decode(encoding)).map_err(|err| err.to_string()));
+        }
+        objects
+    }
+
+    fn objects_by_raw(dump: &[u8]) -> Vec<Result<Object, String>> {
+        Propdump::new(dump).unwrap().map(|object| object.map_err(|err| err.to_string())).collect()
+    }
+
+    fn describe(objects: Vec<Result<Object, String>>) -> Vec<Result<String, String>> {
+        objects.into_iter().map(|object| object.map(|object| format!("{:?}", object))).collect()
+    }
+
+    #[test]
+    fn records_split_by_length_parse_like_records_read_directly() {
+        let objects = objects_by_bytes(V4);
+        assert_eq!(objects.len(), 3);
+        let first = objects[0].as_ref().unwrap();
+        assert_eq!((first.x, first.z, &first.name[..], &first.desc[..], &first.data[..]), (-1500, 2500, "tree.rwx", "hello\nthere", &[0x01, 0xFF][..]));
+        assert_eq!(objects[2].as_ref().unwrap().name, "a.r");
+        assert_eq!(describe(objects), describe(objects_by_raw(V4)));
+    }
+
+    #[test]
+    fn unix_line_endings_and_extra_whitespace_read_the_same() {
+        let pairs: &[(&[u8], &[u8])] = &[
+            (b"propdump version 3\r\n\
+                1 100 -1500 0 2500 0 0 0 8 11 0 tree.rwxhello\x7Fthere\r\n\
+                2 200 10 20 30 1 2 3 7 0 16 sign.rwcreate color red\r\n",
+             b"propdump version 3\n\
+                1  100\t-1500 0  2500 0 0 0 8 11 0 tree.rwxhello\x7Fthere\n\
+                \n\
+                2\t\t200 10 20 30 1 2 3  7 0 16 sign.rwcreate color red \t\n"),
+            (b"propdump version 5\r\n\
+                1 100 -1500 0 2500 0 0 0 2 8 12 0 2 tree.rwxh\xC3\xA9llo\x7Fthere01FF\r\n\
+                2 200 10 20 30 1 2 3 1 7 0 16 0 sign.rwcreate color red\r\n",
+             b"propdump version 5\n\
+                1 100 -1500  0 2500 0 0 0 2 \t8 12 0 2 tree.rwxh\xC3\xA9llo\x7Fthere01FF  \n\
+                \n\
+                2 200 10 20 30 1 2 3 1 7 0 16 0 sign.rwcreate color red\n")
+        ];
+        for &(windows, unix) in pairs {
+            let expected = describe(objects_by_raw(windows));
+            assert_eq!(expected.len(), 2);
+            assert!(expected.iter().all(Result::is_ok), "{:?}", expected);
+            assert_eq!(describe(objects_by_raw(unix)), expected);
+            assert_eq!(describe(objects_by_bytes(unix)), expected);
+        }
+        let v5 = objects_by_raw(pairs[1].1);
+        let first = v5[0].as_ref().unwrap();
+        assert_eq!((first.x, &first.desc[..], &first.data[..]), (-1500, "héllo\nthere", &[0x01, 0xFF][..]));
+    }
+
+    #[test]
+    fn the_first_problem_is_reported() {
+        let damaged: &[&[u8]] = &[
+            b"propdump version 3\r\n1 x 0 0 0 0 0 0 3 0 0 a.r\r\n",
+            b"propdump version 3\r\n1 x 0 0 0 0 0 0 y 0 0 a.r\r\n",
+            b"propdump version 3\r\n1 2 3\r\n4 5 6 7 8 3 0 0 a.r\r\n",
+            b"propdump version 3\r\n1 2 99999999 0 0 0 0 0 3 0 0 a.r\r\n",
+            b"propdump version 4\r\n1 2 0 0 0 0 0 0 2 3 0 0 1 a.rZZ\r\n",
+            b"propdump version 3\r\n1 2 0 0 0 0 0 0 3 0 0 a.r trailing\r\n",
+            b"propdump version 3\r\n1 2 0 0 0 0 0 0 9 0 0 a.r\r\n"
+        ];
+        for dump in damaged {
+            let by_bytes = describe(objects_by_bytes(dump));
+            assert!(by_bytes.last().unwrap().is_err(), "{:?}", by_bytes);
+            assert_eq!(by_bytes, describe(objects_by_raw(dump)));
+        }
+    }
+
+    #[test]
+    fn the_encoding_sample_stops_at_the_limit_without_line_breaks() {
+        let dump = vec![b'x'; SAMPLE_BYTES * 3];
+        let mut file = Replay::new(&dump[..]);
+        assert_eq!(sample(&mut file).unwrap().len(), SAMPLE_BYTES);
+        let mut read = Vec::new();
+        file.read_to_end(&mut read).unwrap();
+        assert_eq!(read, dump);
+    }
+
+    fn recovered(dump: &[u8]) -> (Vec<String>, u64) {
+        let mut propdump = Propdump::new(dump).unwrap();
+        propdump.set_recover(true);
+        let encoding = propdump.encoding();
+        let mut names = Vec::new();
+        while let Some(record) = propdump.next_bytes() {
+            names.push(record.and_then(|record| record.parse()).unwrap().decode(encoding).name);
+        }
+        (names, propdump.lost_records())
+    }
+
+    #[test]
+    fn recovering_finds_records_in_damage_without_line_breaks() {
+        let mut dump = b"propdump version 3\r\n1 2 0 0 0 0 0 0 3 0 0 a.r\n".to_vec();
+        dump.extend(vec![b'x'; 100000]);
+        dump.extend_from_slice(b"1 2 0 0 0 0 0 0 3 0 0 b.r");
+        assert_eq!(recovered(&dump), (vec!["a.r".to_string(), "b.r".to_string()], 1));
+    }
+
+    #[test]
+    fn recovering_goes_back_over_long_damaged_records_in_linear_time() {
+        // Every try after the first goes back over the name the damaged record claims to have
+        let mut dump = b"propdump version 3\r\n1 2 0 0 0 0 0 0 3 0 0 a.r\r\n1 2 0 0 0 0 0 0 500000 0 0 ".to_vec();
+        dump.extend(vec![b'x'; 500000]);
+        dump.extend_from_slice(b" trailing\r\n1 2 0 0 0 0 0 0 3 0 0 b.r\r\n");
+        assert_eq!(recovered(&dump), (vec!["a.r".to_string(), "b.r".to_string()], 1));
+    }
+
+    #[test]
+    fn recovering_counts_the_records_lost() {
+        let dump = b"propdump version 3\r\n\
+            1 2 0 0 0 0 0 0 3 0 0 a.r\r\n\
+            1 2 0 0 0 0 0 0 3 0 0 a.r trailing\r\n\
+            junk junk\r\n\
+            1 2 0 0 0 0 0 0 9 0 0 b.r\r\n\
+            1 2 0 0 0 0 0 0 3 0 0 c.r\r\n\
+            junk\r\n";
+        assert_eq!(recovered(dump), (vec!["a.r".to_string(), "c.r".to_string()], 3));
+    }
+
+    #[test]
+    fn recovering_reads_ahead_no_further_than_the_limit() {
+        let mut fields = FieldReader {
+            file: &b"1 2 0 0 0 0 0 0 2000000 0 0 a.r\r\n"[..],
+            offset: 0,
+            record: 1,
+            max_len: MAX_RECOVER_LEN
+        };
+        match fields.read_record(Schema::of(3).unwrap()).err() {
+            Some(ParseError { cause: ParseErrorCause::TooLong(2000000), offset: 16, .. }) => (),
+            other => panic!("{:?}", other)
+        }
+        assert_eq!(fields.offset, 28);
+    }
+
+    fn written(version: u8, objects: &[Object]) -> (Vec<u8>, u64, u64) {
+        let mut dump = Vec::new();
+        let (stripped, lossy) = {
+            let mut writer = PropdumpWriter::new(&mut dump, version).unwrap();
+            for object in objects {
+                writer.write(object).unwrap();
+            }
+            writer.flush().unwrap();
+            (writer.stripped, writer.lossy)
+        };
+        (dump, stripped, lossy)
+    }
+
+    fn sample_objects(types: bool) -> Vec<Object> {
+        vec![
+            Object {
+                citnum: 104, time: 1_000_000_000, x: -1500, y: 20, z: 2500, yaw: 900, tilt: -10, roll: 5,
+                type_: if types { 2 } else { 0 },
+                name: "tree.rwx".to_string(),
+                desc: "two\nlines\r\nand a break".to_string(),
+                action: "create sign; activate url http://a.b".to_string(),
+                data: if types { vec![0x00, 0x7F, 0xFF] } else { Vec::new() },
+                ..Object::default()
+            },
+            Object { citnum: 1, x: 99_999, z: -99_999, name: "a  b.rwx ".to_string(), ..Object::default() }
+        ]
+    }
+
+    fn read_back(dump: &[u8]) -> Vec<Object> {
+        Propdump::new(dump).unwrap().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn written_propdumps_read_back_the_same() {
+        for &(version, types) in &[(3, false), (4, true), (5, true)] {
+            let mut objects = sample_objects(types);
+            if version == 4 {
+                objects[1].desc = "Café crème".to_string();
+            }
+            if version == 5 {
+                objects[1].desc = "Привет 字".to_string();
+            }
+            let (dump, stripped, lossy) = written(version, &objects);
+            assert!(dump.starts_with(format!("propdump version {}\r\n", version).as_bytes()));
+            assert_eq!((stripped, lossy), (0, 0));
+            assert_eq!(format!("{:?}", read_back(&dump)), format!("{:?}", objects), "version {}", version);
+        }
+    }
+
+    #[test]
+    fn what_the_version_cannot_hold_is_counted() {
+        let mut objects = sample_objects(true);
+        objects[1].name = "字.rwx".to_string();
+        let (dump, stripped, lossy) = written(3, &objects);
+        assert_eq!((stripped, lossy), (1, 1));
+        let read = read_back(&dump);
+        assert_eq!((read[0].type_, read[0].data.len()), (0, 0));
+        assert_eq!(read[1].name, "?.rwx");
+    }
+}
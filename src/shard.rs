@@ -0,0 +1,244 @@
+use failure;
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use cache::{Cache, CellKey, CellSink, CELL_DATA};
+use ctree;
+
+fn shard_name(name: &str, shard: usize) -> String {
+    if shard == 0 {
+        name.to_string()
+    } else {
+        format!("{}.{:03}", name, shard)
+    }
+}
+
+struct Region {
+    shard: usize,
+    /// Cells written so far
+    cells: HashSet<(i16, i16)>
+}
+
+/// Writes cells into cell.idx/dat, moving on to cell.001.idx/dat, cell.002.idx/dat and so on
+/// whenever the 2GB AW limit is hit. Which shard has which regions is listed in cell.shards.txt by `finish`.
+///
+/// The world is split into square regions `region` cells wide, and every cell of a region goes to the same
+/// shard. New regions go to the newest shard. When a region no longer fits in its shard, it is moved as a whole
+/// into the newest shard, or into a new shard if the newest one is the full one or has no room for it either.
+pub struct ShardedCache {
+    /// Name of the first shard, such as cell
+    name: String,
+    region: i16,
+    shards: Vec<Cache>,
+    regions: BTreeMap<(i16, i16), Region>
+}
+
+impl ShardedCache {
+    pub fn new(name: &str, region: i16) -> Result<Self, failure::Error> {
+        Ok(ShardedCache {
+            name: name.to_string(),
+            region,
+            shards: vec![Cache::create(&shard_name(name, 0))?],
+            regions: BTreeMap::new()
+        })
+    }
+
+    fn region_of(&self, cell_x: i16, cell_z: i16) -> (i16, i16) {
+        (cell_x.div_euclid(self.region), cell_z.div_euclid(self.region))
+    }
+
+    /// Copies every cell of a region from one shard to another
+    fn copy_region(&self, region: (i16, i16), from: usize, to: usize) -> Result<(), ctree::Error> {
+        let (from, to) = (&self.shards[from], &self.shards[to]);
+        for &(cell_x, cell_z) in &self.regions[&region].cells {
+            let key = CellKey::new(CELL_DATA, cell_x, cell_z).to_bytes();
+            if let Some(data) = ctree::get(&from.idx, &from.dat, &key)? {
+                ctree::insert_or_append(&to.idx, &to.dat, &key, &data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_region(&self, region: (i16, i16), shard: usize) -> Result<(), ctree::Error> {
+        let shard = &self.shards[shard];
+        for &(cell_x, cell_z) in &self.regions[&region].cells {
+            ctree::take(&shard.idx, &shard.dat, &CellKey::new(CELL_DATA, cell_x, cell_z).to_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Moves every cell of a region out of its full shard
+    fn move_region(&mut self, region: (i16, i16)) -> Result<(), failure::Error> {
+        let from = self.regions[&region].shard;
+        let newest = self.shards.len() - 1;
+        if from != newest {
+            match self.copy_region(region, from, newest) {
+                Ok(()) => {
+                    self.remove_region(region, from)?;
+                    self.regions.get_mut(&region).unwrap().shard = newest;
+                    return Ok(());
+                },
+                Err(ctree::Error::OutOfSpace) => self.remove_region(region, newest)?,
+                Err(err) => return Err(err.into())
+            }
+        }
+        let shard = self.shards.len();
+        eprintln!("Hit 2GB AW limit, continuing in {}", shard_name(&self.name, shard));
+        self.shards.push(Cache::create(&shard_name(&self.name, shard))?);
+        match self.copy_region(region, from, shard) {
+            Err(ctree::Error::OutOfSpace) => bail!("Region {:?} does not fit in 2GB by itself, try a smaller region size", region),
+            result => result?
+        }
+        self.remove_region(region, from)?;
+        self.regions.get_mut(&region).unwrap().shard = shard;
+        Ok(())
+    }
+
+    fn write_manifest(&self) -> Result<(), failure::Error> {
+        let path = format!("{}.shards.txt", self.name);
+        let mut manifest = BufWriter::new(File::create(&path).map_err(|err| format_err!("Unable to write {}: {}", path, err))?);
+        writeln!(manifest, "# shard min_cell_x min_cell_z max_cell_x max_cell_z")?;
+        for shard in 0..self.shards.len() {
+            for (&(region_x, region_z), _) in self.regions.iter().filter(|region| region.1.shard == shard) {
+                let min_x = i32::from(region_x) * i32::from(self.region);
+                let min_z = i32::from(region_z) * i32::from(self.region);
+                let max_x = min_x + i32::from(self.region) - 1;
+                let max_z = min_z + i32::from(self.region) - 1;
+                writeln!(manifest, "{} {} {} {} {}", shard_name(&self.name, shard), min_x, min_z, max_x, max_z)?;
+            }
+        }
+        manifest.flush()?;
+        Ok(())
+    }
+}
+
+impl CellSink for ShardedCache {
+    fn write_cell(&mut self, cell_x: i16, cell_z: i16, data: &[u8]) -> Result<(), failure::Error> {
+        let region = self.region_of(cell_x, cell_z);
+        let newest = self.shards.len() - 1;
+        self.regions.entry(region).or_insert_with(|| Region { shard: newest, cells: HashSet::new() });
+        let key = CellKey::new(CELL_DATA, cell_x, cell_z).to_bytes();
+        let shards = self.shards.len();
+        loop {
+            let shard = &self.shards[self.regions[&region].shard];
+            match ctree::insert_or_append(&shard.idx, &shard.dat, &key, data) {
+                Err(ctree::Error::OutOfSpace) => {
+                    ensure!(self.shards.len() == shards, "Region {:?} does not fit in 2GB by itself, try a smaller region size", region);
+                    self.move_region(region)?;
+                },
+                result => break result?
+            }
+        }
+        self.regions.get_mut(&region).unwrap().cells.insert((cell_x, cell_z));
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), failure::Error> {
+        self.write_manifest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::{Read, Seek, SeekFrom};
+    use std::process;
+    use byteorder::{ByteOrder, LE};
+
+    use super::*;
+
+    /// Shards in the temporary directory, removed again when dropped
+    struct Scratch(String);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            Scratch(env::temp_dir().join(format!("propdump2cell42-{}-{}", name, process::id())).to_str().unwrap().to_string())
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            for shard in 0..3 {
+                let _ = fs::remove_file(format!("{}.idx", shard_name(&self.0, shard)));
+                let _ = fs::remove_file(format!("{}.dat", shard_name(&self.0, shard)));
+            }
+            let _ = fs::remove_file(format!("{}.shards.txt", self.0));
+        }
+    }
+
+    /// Makes a data file look all but full, with one record taking up the space up to `room` bytes short of the
+    /// 2GB limit. The file stays small, as nothing is written in between.
+    fn fill(name: &str, room: u32) {
+        let mut dat = OpenOptions::new().read(true).write(true).open(format!("{}.dat", name)).unwrap();
+        let mut header = [0u8; 12];
+        dat.read_exact(&mut header).unwrap();
+        // The header starts with the last byte of the file, then the last byte in use at 8
+        let end = LE::read_u32(&header[8..12]);
+        let full = ctree::DAT_LIMIT as u32 - room;
+        let mut record = [0u8; 10];
+        LE::write_u16(&mut record[0..2], 0xFAFA);
+        LE::write_u32(&mut record[2..6], full - end);
+        dat.seek(SeekFrom::Start(u64::from(end) + 1)).unwrap();
+        dat.write_all(&record).unwrap();
+        LE::write_u32(&mut header[0..4], full);
+        LE::write_u32(&mut header[8..12], full);
+        dat.seek(SeekFrom::Start(0)).unwrap();
+        dat.write_all(&header).unwrap();
+    }
+
+    /// The cells with object data in a shard
+    fn cells(name: &str) -> Vec<(i16, i16)> {
+        let cache = Cache::open(name).unwrap();
+        let cells = cache.cells().map(|cell| cell.unwrap().key).filter(|key| key.kind == CELL_DATA).map(|key| (key.cell_x, key.cell_z)).collect();
+        cells
+    }
+
+    #[test]
+    fn full_shards_move_whole_regions_into_the_next() {
+        let scratch = Scratch::new("shards");
+        let mut sharded = ShardedCache::new(&scratch.0, 2).unwrap();
+        sharded.shards.clear();
+        fill(&scratch.0, 1500);
+        sharded.shards.push(Cache::open(&scratch.0).unwrap());
+
+        let data = [7u8; 1000];
+        // Two records still start below the limit
+        sharded.write_cell(0, 0, &data).unwrap();
+        sharded.write_cell(2, 0, &data).unwrap();
+        assert_eq!(sharded.shards.len(), 1);
+        // A new region goes to a new shard, and a region that no longer fits follows it there
+        sharded.write_cell(4, 0, &data).unwrap();
+        assert_eq!(sharded.shards.len(), 2);
+        sharded.write_cell(1, 1, &data).unwrap();
+        sharded.write_cell(-1, -3, &data).unwrap();
+        sharded.finish().unwrap();
+        drop(sharded);
+
+        let second = shard_name(&scratch.0, 1);
+        assert_eq!(cells(&scratch.0), vec![(2, 0)]);
+        let mut moved = cells(&second);
+        moved.sort();
+        assert_eq!(moved, vec![(-1, -3), (0, 0), (1, 1), (4, 0)]);
+        let manifest = fs::read_to_string(format!("{}.shards.txt", scratch.0)).unwrap();
+        assert_eq!(manifest.lines().collect::<Vec<_>>(), vec![
+            "# shard min_cell_x min_cell_z max_cell_x max_cell_z".to_string(),
+            format!("{} 2 0 3 1", scratch.0),
+            format!("{} -2 -4 -1 -3", second),
+            format!("{} 0 0 1 1", second),
+            format!("{} 4 0 5 1", second)
+        ]);
+    }
+
+    #[test]
+    fn regions_are_floored() {
+        let scratch = Scratch::new("regions");
+        let sharded = ShardedCache::new(&scratch.0, 16).unwrap();
+        assert_eq!(sharded.region_of(0, 15), (0, 0));
+        assert_eq!(sharded.region_of(16, -1), (1, -1));
+        assert_eq!(sharded.region_of(-16, -17), (-1, -2));
+        assert_eq!(sharded.region_of(i16::MIN, i16::MAX), (-2048, 2047));
+    }
+}
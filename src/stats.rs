@@ -0,0 +1,216 @@
+//! Figures about the objects of a propdump, for planning what fits in a cache
+
+use failure;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+use aw::{EncodedText, Object};
+use cache;
+use ctree;
+use objdata;
+
+/// Entries listed in each of the top lists
+const TOP: usize = 20;
+
+/// What one citizen has built
+struct Citizen {
+    objects: u64,
+    bytes: u64,
+    /// Lowest and highest cell x and z
+    min_cell: (i16, i16),
+    max_cell: (i16, i16)
+}
+
+#[derive(Default)]
+pub struct Stats {
+    objects: u64,
+    citizens: HashMap<i32, Citizen>,
+    /// Objects and bytes of object data in every cell
+    cells: HashMap<(i16, i16), (u64, u64)>,
+    types: BTreeMap<i32, u64>,
+    /// Earliest and latest object time
+    times: Option<(i32, i32)>,
+    models: HashMap<String, u64>,
+    object_buffer: Vec<u8>
+}
+
+impl Stats {
+    /// Counts an object, with its text as `FieldLimits` encoded it for the cache
+    pub fn add(&mut self, object: &Object, text: &EncodedText) -> Result<(), failure::Error> {
+        self.object_buffer.clear();
+        object.write_encoded(&mut self.object_buffer, text)?;
+        let bytes = self.object_buffer.len() as u64;
+        let loc = object.location();
+        let cell = (loc.cell_x, loc.cell_z);
+        self.objects += 1;
+        let citizen = self.citizens.entry(object.citnum).or_insert(Citizen {
+            objects: 0,
+            bytes: 0,
+            min_cell: cell,
+            max_cell: cell
+        });
+        citizen.objects += 1;
+        citizen.bytes += bytes;
+        citizen.min_cell = (citizen.min_cell.0.min(cell.0), citizen.min_cell.1.min(cell.1));
+        citizen.max_cell = (citizen.max_cell.0.max(cell.0), citizen.max_cell.1.max(cell.1));
+        let totals = self.cells.entry(cell).or_insert((0, 0));
+        totals.0 += 1;
+        totals.1 += bytes;
+        *self.types.entry(object.type_).or_insert(0) += 1;
+        self.times = Some(match self.times {
+            Some((first, last)) => (first.min(object.time), last.max(object.time)),
+            None => (object.time, object.time)
+        });
+        if let Some(count) = self.models.get_mut(&object.name) {
+            *count += 1;
+        } else {
+            self.models.insert(object.name.clone(), 1);
+        }
+        Ok(())
+    }
+
+    /// Roughly how big cell.dat would get holding every object
+    pub fn estimated_dat_size(&self) -> u64 {
+        cache::estimate_dat_size(self.cells.len() as u64, self.cells.values().map(|&(_, bytes)| bytes).sum())
+    }
+
+    /// Writes the report. The object type histogram is left out for propdumps without object types.
+    pub fn report<W: Write>(&self, mut w: W, types: bool) -> io::Result<()> {
+        writeln!(w, "Objects: {}", self.objects)?;
+        if let Some((first, last)) = self.times {
+            writeln!(w, "Times: {} to {}", date(first), date(last))?;
+        }
+        let size = self.estimated_dat_size();
+        let fits = if size <= ctree::DAT_LIMIT as u64 { "fits in" } else { "over" };
+        writeln!(w, "Estimated cell.dat size: {} bytes, {} the 2GB AW limit", size, fits)?;
+
+        let mut cells: Vec<_> = self.cells.iter().collect();
+        cells.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then(a.0.cmp(b.0)));
+        writeln!(w, "\nDensest cells, of {}:", cells.len())?;
+        for (&(cell_x, cell_z), &(objects, bytes)) in cells.into_iter().take(TOP) {
+            writeln!(w, "    Cell {} {}: {} objects, {} bytes", cell_x, cell_z, objects, bytes)?;
+        }
+
+        let mut models: Vec<_> = self.models.iter().collect();
+        models.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        writeln!(w, "\nMost used models, of {}:", models.len())?;
+        for (name, objects) in models.into_iter().take(TOP) {
+            writeln!(w, "    {:?}: {} objects", name, objects)?;
+        }
+
+        if types {
+            writeln!(w, "\nObject types:")?;
+            for (type_, objects) in &self.types {
+                match objdata::type_name(*type_) {
+                    Some(name) => writeln!(w, "    Type {} ({}): {} objects", type_, name, objects)?,
+                    None => writeln!(w, "    Type {}: {} objects", type_, objects)?
+                }
+            }
+        }
+
+        let mut citizens: Vec<_> = self.citizens.iter().collect();
+        citizens.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then(a.0.cmp(b.0)));
+        writeln!(w, "\nCitizens, by bytes:")?;
+        for (citnum, citizen) in citizens {
+            writeln!(w, "    Citizen {}: {} objects, {} bytes, cells {} {} to {} {}", citnum, citizen.objects, citizen.bytes,
+                     citizen.min_cell.0, citizen.min_cell.1, citizen.max_cell.0, citizen.max_cell.1)?;
+        }
+        Ok(())
+    }
+}
+
+/// A Unix time as a UTC date and time
+fn date(time: i32) -> String {
+    let time = i64::from(time);
+    let (days, seconds) = (time.div_euclid(86400), time.rem_euclid(86400));
+    // Days to a civil date, counting in 400 year eras starting on March 1st
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codepage::CodePage;
+
+    fn object(citnum: i32, type_: i32, time: i32, x: i32, z: i32, name: &str) -> Object {
+        Object {
+            type_,
+            citnum,
+            time,
+            x,
+            z,
+            name: name.to_string(),
+            ..Object::default()
+        }
+    }
+
+    /// Bytes the object takes in the cache
+    fn bytes(object: &Object) -> u64 {
+        let mut buffer = Vec::new();
+        object.write_encoded(&mut buffer, &object.encode_text(&CodePage::default())).unwrap();
+        buffer.len() as u64
+    }
+
+    #[test]
+    fn objects_are_counted_by_citizen_cell_type_and_model() {
+        let mut objects = vec![
+            object(1, 1, 86400, 500, 0, "tree.rwx"),
+            object(1, 1, 100, -1500, 2500, "tree.rwx"),
+            object(2, 99, 86400 * 365, 0, 999, "wall.rwx"),
+            object(2, 2, 200, 0, 0, "tree.rwx")
+        ];
+        // Puts citizen 2 first by bytes
+        objects[2].desc = "x".repeat(100);
+        let mut stats = Stats::default();
+        for object in &objects {
+            stats.add(object, &object.encode_text(&CodePage::default())).unwrap();
+        }
+        let sizes: Vec<u64> = objects.iter().map(bytes).collect();
+        let mut report = Vec::new();
+        stats.report(&mut report, true).unwrap();
+        assert_eq!(String::from_utf8(report).unwrap(), format!("\
+Objects: 4
+Times: 1970-01-01 00:01:40 UTC to 1971-01-01 00:00:00 UTC
+Estimated cell.dat size: {} bytes, fits in the 2GB AW limit
+
+Densest cells, of 2:
+    Cell 0 0: 3 objects, {} bytes
+    Cell -2 2: 1 objects, {} bytes
+
+Most used models, of 2:
+    \"tree.rwx\": 3 objects
+    \"wall.rwx\": 1 objects
+
+Object types:
+    Type 1 (v3 object): 2 objects
+    Type 2 (v4 object): 1 objects
+    Type 99: 1 objects
+
+Citizens, by bytes:
+    Citizen 2: 2 objects, {} bytes, cells 0 0 to 0 0
+    Citizen 1: 2 objects, {} bytes, cells -2 0 to 0 2
+",
+            cache::estimate_dat_size(2, sizes.iter().sum()), sizes[0] + sizes[2] + sizes[3], sizes[1],
+            sizes[2] + sizes[3], sizes[0] + sizes[1]));
+    }
+
+    #[test]
+    fn propdumps_without_types_get_no_type_histogram() {
+        let mut stats = Stats::default();
+        let object = object(1, 0, 0, 0, 0, "tree.rwx");
+        stats.add(&object, &object.encode_text(&CodePage::default())).unwrap();
+        let mut report = Vec::new();
+        stats.report(&mut report, false).unwrap();
+        assert!(!String::from_utf8(report).unwrap().contains("Object types"));
+    }
+}
@@ -3,11 +3,21 @@ use aw::Object;
 use regex::Regex;
 
 use std::io::Write;
+use std::ops::RangeInclusive;
 
 pub struct Teleports {
     regions: Vec<((i16, i16), (i16, i16))>
 }
 
+/// A location from a teleport file
+#[derive(Debug, Clone)]
+pub struct Teleport {
+    /// The coordinates as written in the file
+    pub coords: String,
+    pub x: i16,
+    pub z: i16
+}
+
 fn coord_to_num<S: AsRef<str>>(coord: S) -> Result<i16, failure::Error> {
     use std::str::FromStr;
 
@@ -15,47 +25,69 @@ fn coord_to_num<S: AsRef<str>>(coord: S) -> Result<i16, failure::Error> {
     let (digits, indicator) = coord.split_at(coord.len() - 1);
     let indicator = indicator.to_uppercase();
     let floating = f32::from_str(digits)?;
-    let integer = floating as i16;
-    if indicator == "N" || indicator == "W" {
-        return Ok(integer);
+    // Rounded down like object positions, so 0.5S is in cell -1
+    let signed = if indicator == "N" || indicator == "W" {
+        floating
     } else if indicator == "S" || indicator == "E" {
-        return Ok(-integer);
+        -floating
     } else {
         bail!("Unable to process coordinate in teleport file!");
-    }
+    };
+    let cell = signed.floor();
+    ensure!((f32::from(i16::MIN)..=f32::from(i16::MAX)).contains(&cell), "Coordinate {} in teleport file is beyond the last cell", coord);
+    Ok(cell as i16)
 }
 
 fn bounds(coord: i16, radius: i16) -> (i16, i16) {
     (coord.saturating_sub(radius), coord.saturating_add(radius))
 }
 
+/// Reads the locations of a teleport file, in order
+pub fn read_file<P: AsRef<::std::path::Path>>(path: P) -> Result<Vec<Teleport>, failure::Error> {
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::io::BufReader;
+
+    let mut teleports = Vec::new();
+
+    let file = File::open(path)?;
+    let buffer = BufReader::new(file);
+
+    for line in buffer.lines() {
+        let line = line?;
+        let coords = line.split(':').next().expect("Unable to split on : in teleport fille!");
+        let mut data = coords.split(' ');
+        let _world = data.next();
+        let ns = data.next();
+        let ew = data.next();
+        ensure!(ns.is_some() && ew.is_some(), "Unable to process line in teleport file!");
+        let z = coord_to_num(ns.unwrap())?;
+        let x = coord_to_num(ew.unwrap())?;
+        teleports.push(Teleport {
+            coords: format!("{} {}", ns.unwrap(), ew.unwrap()),
+            x,
+            z
+        });
+    }
+
+    Ok(teleports)
+}
+
 impl Teleports {
     pub fn from_file<P: AsRef<::std::path::Path>>(path: P, radius: i16) -> Result<Self, failure::Error> {
-        use std::fs::File;
-        use std::io::prelude::*;
-        use std::io::BufReader;
-        
-        let mut this = Teleports {
-            regions: Vec::new()
-        };
-        
-        let file = File::open(path)?;
-        let buffer = BufReader::new(file);
-        
-        for line in buffer.lines() {
-            let mut line = line?;
-            let mut coords = line.split(':').next().expect("Unable to split on : in teleport fille!");
-            let mut data = coords.split(' ');
-            let _world = data.next();
-            let ns = data.next();
-            let ew = data.next();
-            ensure!(ns.is_some() && ew.is_some(), "Unable to process line in teleport file!");
-            let z = coord_to_num(ns.unwrap())?;
-            let x = coord_to_num(ew.unwrap())?;
-            this.regions.push((bounds(x, radius), bounds(z, radius)));
+        Ok(Teleports::around(read_file(path)?.iter().map(|teleport| (teleport, radius))))
+    }
+
+    /// Areas around teleports, each with its own radius
+    pub fn around<'a, I: IntoIterator<Item=(&'a Teleport, i16)>>(teleports: I) -> Self {
+        Teleports {
+            regions: teleports.into_iter().map(|(teleport, radius)| (bounds(teleport.x, radius), bounds(teleport.z, radius))).collect()
         }
-        
-        Ok(this)
+    }
+
+    /// The areas to include, as x and z cell ranges
+    pub fn regions(&self) -> impl Iterator<Item=(RangeInclusive<i16>, RangeInclusive<i16>)> + '_ {
+        self.regions.iter().map(|&((min_x, max_x), (min_z, max_z))| (min_x..=max_x, min_z..=max_z))
     }
 
     pub fn contains(&self, object: &Object) -> bool {
@@ -65,7 +97,7 @@ impl Teleports {
                 return true;
             }
         }
-        return false;
+        false
     }
 }
 
@@ -74,20 +106,44 @@ pub struct TeleportAppender {
     world: String
 }
 
+/// Finds the teleports and warps into a world in object actions
+#[derive(Clone)]
+pub struct TeleportScanner {
+    world: String
+}
+
 impl TeleportAppender {
     pub fn from_file<P: AsRef<::std::path::Path>, S: AsRef<str>>(path: P, world: S) -> Result<Self, failure::Error> {
         use std::fs::OpenOptions;
         let file = OpenOptions::new().create(true).append(true).open(path)?;
         Ok(TeleportAppender {
-            file: file,
+            file,
             world: world.as_ref().to_uppercase()
         })
     }
-    
-    pub fn check_to_append(&mut self, object: &Object) -> Result<(), failure::Error> {
+
+    pub fn scanner(&self) -> TeleportScanner {
+        TeleportScanner {
+            world: self.world.clone()
+        }
+    }
+
+    /// Appends lines found by the scanner
+    pub fn append(&mut self, found: &[String]) -> Result<(), failure::Error> {
+        for line in found {
+            writeln!(&mut self.file, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl TeleportScanner {
+    /// The lines to append for the teleports in an object's action
+    pub fn scan(&self, object: &Object) -> Vec<String> {
         lazy_static! {
             static ref RE: Regex = Regex::new(r"(?i)\W(teleportx?|warp)\W+((?P<world>\w+)\W+)?(?P<ns>[0-9.]+(n|s))\W+(?P<ew>[0-9.]+(e|w))").unwrap();
         }
+        let mut found = Vec::new();
         for capture in RE.captures_iter(&object.action) {
             if let Some(world) = capture.name("world") {
                 if world.as_str().to_uppercase() != self.world {
@@ -96,8 +152,8 @@ impl TeleportAppender {
             }
             let ns = capture.name("ns").expect("Couldn't find coords in teleport").as_str();
             let ew = capture.name("ew").expect("Couldn't find coords in teleport").as_str();
-            writeln!(&mut self.file, "{} {} {}: ZZZFound", &self.world, ns, ew)?;
+            found.push(format!("{} {} {}: ZZZFound", &self.world, ns, ew));
         }
-        Ok(())
+        found
     }
 }